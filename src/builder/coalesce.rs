@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use hyper::{body::HttpBody, Body, HeaderMap};
+use tokio::sync::{oneshot, watch};
+
+use super::core::ResourceData;
+
+/// Identifies requests that share one call to the remote.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    method: hyper::Method,
+    uri: String,
+}
+
+impl Key {
+    /// Creates the key of a request. Only GET and HEAD requests are shared, so nothing is
+    /// returned for other methods.
+    pub fn new(method: &hyper::Method, uri: &str) -> Option<Self> {
+        if method != hyper::Method::GET && method != hyper::Method::HEAD {
+            return None;
+        }
+
+        Some(Self {
+            method: method.clone(),
+            uri: uri.to_owned(),
+        })
+    }
+}
+
+/// The response of the remote that is shared with a waiting request
+pub struct Shared {
+    /// HTTP status code
+    pub code: u16,
+    /// HTTP headers
+    pub headers: HeaderMap,
+    /// A copy of the body that is streamed while the leader receives it
+    pub body: Body,
+}
+
+/// The result of a flight for a waiting request. Without a response it gets the status code that
+/// the leader answered with.
+type Outcome = Result<Shared, u16>;
+
+/// A request to the remote that is currently running.
+struct Entry {
+    /// Requests that wait for the response. Nothing once the leader got it, requests that join
+    /// afterwards wait for the end of the flight.
+    waiting: Option<Vec<oneshot::Sender<Outcome>>>,
+    /// Closed when the flight ends
+    done: watch::Receiver<()>,
+}
+
+fn flights() -> &'static Mutex<HashMap<Key, Entry>> {
+    static FLIGHTS: OnceLock<Mutex<HashMap<Key, Entry>>> = OnceLock::new();
+    FLIGHTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The role of a request in a flight.
+pub enum Flight {
+    /// Calls the remote and records the response. The flight ends when the guard is dropped.
+    Leader(Guard),
+    /// Waits for the leader
+    Follower(Follower),
+}
+
+/// Joins the running flight of the key or starts a new one.
+pub fn join(key: Key) -> Flight {
+    let mut flights = flights().lock().unwrap();
+    if let Some(entry) = flights.get_mut(&key) {
+        let outcome = entry.waiting.as_mut().map(|waiting| {
+            let (sender, receiver) = oneshot::channel();
+            waiting.push(sender);
+            receiver
+        });
+        return Flight::Follower(Follower {
+            outcome,
+            done: entry.done.clone(),
+        });
+    }
+
+    let (sender, done) = watch::channel(());
+    let entry = Entry {
+        waiting: Some(vec![]),
+        done,
+    };
+    flights.insert(key.clone(), entry);
+
+    Flight::Leader(Guard {
+        key,
+        _sender: sender,
+    })
+}
+
+/// Ends the flight when it is dropped. Requests that arrive afterwards call the remote again.
+pub struct Guard {
+    key: Key,
+    _sender: watch::Sender<()>,
+}
+
+impl Guard {
+    /// Shares the response with the waiting requests. Each of them gets a copy of the body while
+    /// it is received, so the body is never buffered. The payload is replaced with the copy of
+    /// the leader.
+    pub fn share(&self, response: &mut ResourceData) {
+        let mut followers = vec![];
+        for waiting in self.take_waiting() {
+            let (sender, body) = Body::channel();
+            let shared = Shared {
+                code: response.code,
+                headers: response.headers.clone(),
+                body,
+            };
+            if waiting.send(Ok(shared)).is_ok() {
+                followers.push(sender);
+            }
+        }
+        if followers.is_empty() {
+            return;
+        }
+
+        let (sender, body) = Body::channel();
+        let mut payload = std::mem::replace(&mut response.payload, body);
+        tokio::spawn(async move {
+            let mut leader = Some(sender);
+            while let Some(data) = payload.data().await {
+                let Ok(data) = data else {
+                    leader.into_iter().chain(followers).for_each(|s| s.abort());
+                    return;
+                };
+
+                if let Some(sender) = leader.as_mut() {
+                    if sender.send_data(data.clone()).await.is_err() {
+                        leader = None;
+                    }
+                }
+                let mut connected = Vec::with_capacity(followers.len());
+                for mut follower in followers {
+                    if follower.send_data(data.clone()).await.is_ok() {
+                        connected.push(follower);
+                    }
+                }
+                followers = connected;
+
+                if leader.is_none() && followers.is_empty() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Lets the waiting requests answer with the status code, because there is no response.
+    pub fn fail(&self, code: u16) {
+        for waiting in self.take_waiting() {
+            let _ = waiting.send(Err(code));
+        }
+    }
+
+    fn take_waiting(&self) -> Vec<oneshot::Sender<Outcome>> {
+        flights()
+            .lock()
+            .unwrap()
+            .get_mut(&self.key)
+            .and_then(|entry| entry.waiting.take())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // The sender is dropped afterwards, so waiting followers do not find the flight again
+        flights().lock().unwrap().remove(&self.key);
+    }
+}
+
+/// A request that waits for the leader.
+pub struct Follower {
+    outcome: Option<oneshot::Receiver<Outcome>>,
+    done: watch::Receiver<()>,
+}
+
+impl Follower {
+    /// Returns the response or status code that the leader shared. Nothing is returned when the
+    /// request joined after the leader got the response. It waits until the flight ended then,
+    /// so that the request can be handled again and is served from the recording.
+    pub async fn wait(mut self) -> Option<Outcome> {
+        if let Some(outcome) = self.outcome {
+            return outcome.await.ok();
+        }
+
+        while self.done.changed().await.is_ok() {}
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::{body, Body, HeaderMap};
+
+    use crate::{builder::core::ResourceData, configuration::RouteMethod};
+
+    use super::{join, Flight, Key};
+
+    fn response(payload: &'static str) -> ResourceData {
+        ResourceData {
+            method: RouteMethod::GET,
+            headers: HeaderMap::new(),
+            code: 500,
+            payload: Body::from(payload),
+        }
+    }
+
+    #[tokio::test]
+    async fn share_should_stream_the_response_to_waiting_requests() {
+        let key = Key::new(&hyper::Method::GET, "/coalesce/shared").unwrap();
+        let Flight::Leader(guard) = join(key.clone()) else {
+            panic!("first request should call the remote");
+        };
+        let Flight::Follower(follower) = join(key.clone()) else {
+            panic!("second request should wait");
+        };
+
+        let mut response = response("failed");
+        guard.share(&mut response);
+        let shared = follower.wait().await.unwrap().unwrap();
+        let (own, copy) = tokio::join!(
+            body::to_bytes(response.payload),
+            body::to_bytes(shared.body)
+        );
+
+        assert_eq!(shared.code, 500);
+        assert_eq!(own.unwrap(), "failed");
+        assert_eq!(copy.unwrap(), "failed");
+    }
+
+    #[tokio::test]
+    async fn wait_should_return_the_status_of_a_failed_flight() {
+        let key = Key::new(&hyper::Method::GET, "/coalesce/failed").unwrap();
+        let Flight::Leader(guard) = join(key.clone()) else {
+            panic!("first request should call the remote");
+        };
+        let Flight::Follower(follower) = join(key) else {
+            panic!("second request should wait");
+        };
+
+        guard.fail(504);
+
+        assert_eq!(follower.wait().await.unwrap().err(), Some(504));
+    }
+
+    #[tokio::test]
+    async fn join_should_wait_for_the_end_after_the_response() {
+        let key = Key::new(&hyper::Method::GET, "/coalesce/late").unwrap();
+        let Flight::Leader(guard) = join(key.clone()) else {
+            panic!("first request should call the remote");
+        };
+        guard.share(&mut response("recorded"));
+        let Flight::Follower(follower) = join(key.clone()) else {
+            panic!("later request should wait");
+        };
+        let waiting = tokio::spawn(follower.wait());
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        drop(guard);
+        assert!(waiting.await.unwrap().is_none());
+        assert!(matches!(join(key), Flight::Leader(_)));
+    }
+
+    #[test]
+    fn key_should_only_share_get_and_head() {
+        assert!(Key::new(&hyper::Method::HEAD, "/coalesce").is_some());
+        assert!(Key::new(&hyper::Method::POST, "/coalesce").is_none());
+        assert!(Key::new(&hyper::Method::PUT, "/coalesce").is_none());
+    }
+}
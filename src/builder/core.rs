@@ -1,11 +1,17 @@
 use std::{convert::Infallible, sync::Arc};
 
-use hyper::{Body, HeaderMap, Response};
-use tokio::sync::Mutex;
+use hyper::{
+    body::{self, Bytes, HttpBody},
+    header, Body, HeaderMap, Response,
+};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
 
-use crate::configuration::{BuildMode, Configuration, Metadata, RouteMethod};
+use crate::{
+    configuration::{BuildMode, Configuration, Metadata, RecordedRequest, RouteMethod},
+    response::headers,
+};
 
-use super::{request, storage};
+use super::{coalesce, filter, persist, redact, request, storage};
 
 /// The data structure that will contain all relevant data. To easily convert a request to a response
 /// without doing a huge workaround.
@@ -16,99 +22,622 @@ pub struct ResourceData {
     pub headers: HeaderMap,
     /// HTTP status code
     pub code: u16,
+    /// HTTP body that is streamed from the remote
+    pub payload: Body,
+}
+
+/// The request that was sent to the remote. It is saved together with the response.
+pub struct RequestData {
+    /// Method, url and headers of the request
+    pub request: RecordedRequest,
     /// HTTP body
-    pub payload: Option<Vec<u8>>,
+    pub body: Bytes,
 }
 
 /// Handles unknown routes. It accomplishes that with creating HTTP request and saving the response
 /// into a file. It also modifies the configuration in order to not call this function with the
 /// same URL again.
+///
+/// While recording, HEAD requests are sent as GET, so that the recording has the body and length
+/// that later GET and HEAD requests are answered with. The caller drops the body for HEAD.
+///
+/// The response is shared with the requests that wait in the flight of a coalesced request. The
+/// flight ends after the response was recorded.
 pub async fn build_response(
     config_a: Arc<Mutex<Configuration>>,
     uri: &str,
-    method: hyper::Method,
+    mut method: hyper::Method,
     header: HeaderMap,
     body: hyper::Body,
     no_ssl_check: bool,
+    flight: Option<coalesce::Guard>,
 ) -> Result<Response<Body>, Infallible> {
-    let config_b = config_a.clone();
-    let config = config_b.lock().await.to_owned();
-    let Some(build_mode) = &config.build_mode else {
-        tracing::info!("Resource not found and build mode disabled");
-        let response = Response::builder().status(404).body(Body::empty()).unwrap();
-        return Ok(response);
+    let build_mode = config_a.lock().await.build_mode.clone();
+    if method == hyper::Method::HEAD
+        && matches!(build_mode, Some(BuildMode::Write | BuildMode::Refresh))
+    {
+        method = hyper::Method::GET;
+    }
+    let (mut response, request) =
+        match fetch(config_a.clone(), uri, method, header, body, no_ssl_check).await {
+            Ok(fetched) => fetched,
+            Err(code) => {
+                if let Some(flight) = &flight {
+                    flight.fail(code);
+                }
+                let response = Response::builder()
+                    .status(code)
+                    .body(Body::empty())
+                    .unwrap();
+                return Ok(response);
+            }
+        };
+    if let Some(flight) = &flight {
+        flight.share(&mut response);
+    }
+
+    respond(
+        config_a,
+        storage::Destination::new(uri),
+        response,
+        request,
+        flight,
+    )
+    .await
+}
+
+/// Calls the remote for a route that is already recorded and updates the recording of the route
+/// that matched the request. Returns nothing when the remote is unreachable or fails, so that
+/// the recording can be served instead. With `keep_previous` this is also the case for status
+/// codes that are not recorded.
+pub async fn refresh_response(
+    config_a: Arc<Mutex<Configuration>>,
+    uri: &str,
+    route: storage::RefreshedRoute,
+    method: hyper::Method,
+    header: HeaderMap,
+    body: hyper::Body,
+    no_ssl_check: bool,
+) -> Option<Response<Body>> {
+    let (response, request) = fetch(config_a.clone(), uri, method, header, body, no_ssl_check)
+        .await
+        .ok()?;
+    let destination = storage::Destination {
+        route: Some(route),
+        ..storage::Destination::new(uri)
     };
+    let recording = config_a.lock().await.recording.clone().unwrap_or_default();
+    if response.code >= 500
+        || (recording.keep_previous && !filter::is_status_recorded(&recording, response.code))
+    {
+        tracing::info!(
+            "Remote responded with {}, serving the recording of {}",
+            response.code,
+            uri
+        );
+        return None;
+    }
+
+    respond(config_a, destination, response, request, None)
+        .await
+        .ok()
+}
+
+/// Calls the remote. Returns the status code that the client gets when there is no response:
+/// 404 without a remote, 502 when the remote is unreachable and 504 when it does not respond in
+/// time.
+async fn fetch(
+    config_a: Arc<Mutex<Configuration>>,
+    uri: &str,
+    method: hyper::Method,
+    mut header: HeaderMap,
+    body: hyper::Body,
+    no_ssl_check: bool,
+) -> Result<(ResourceData, RequestData), u16> {
+    let config = config_a.lock().await.to_owned();
+    if config.build_mode.is_none() {
+        tracing::info!("Resource not found and build mode disabled");
+        return Err(404);
+    }
     let Some(remote) = &config.remote else {
         tracing::error!("Resource not found and no remove specified");
-        let response = Response::builder().status(404).body(Body::empty()).unwrap();
-        return Ok(response);
+        return Err(404);
     };
-    let response = request::http::fetch_http(
-        RouteMethod::from(method),
-        request::util::get_url(uri, remote),
-        reqwest::Body::from(body),
+    if matches!(
+        config.build_mode,
+        Some(BuildMode::Write | BuildMode::Refresh)
+    ) {
+        // The full resource is recorded, not a 304 or a part of it
+        for key in [
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+            header::IF_RANGE,
+            header::RANGE,
+        ] {
+            header.remove(key);
+        }
+    }
+    let body = match body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Unable to read request body: {}", e);
+            return Err(400);
+        }
+    };
+    request::forward::rewrite_for_remote(
+        &config.forwarding.unwrap_or_default(),
+        &mut header,
+        remote,
+    );
+    let request = RecordedRequest {
+        method: RouteMethod::from(method),
+        url: request::util::get_url(uri, remote),
         header,
-        no_ssl_check
+        body: None,
+    };
+    let settings = config
+        .upstream
+        .unwrap_or_default()
+        .get_settings(&request.url);
+    let response = request::http::fetch_http(
+        request.method.clone(),
+        request.url.clone(),
+        body.clone(),
+        request.header.clone(),
+        no_ssl_check,
+        &settings,
     )
     .await;
 
-    let Some(response) = response else {
-        tracing::error!("No response from endpoint");
-        let response = Response::builder().status(404).body(Body::empty()).unwrap();
-        return Ok(response);
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("No response from endpoint: {:?}", e);
+            return Err(e.status());
+        }
     };
-    let Some(body) = response.payload else {
-      return get_response(response.headers, response.code, Body::empty());
+
+    Ok((response, RequestData { request, body }))
+}
+
+/// Sends the response of the remote to the client and records it when the build mode allows it.
+/// The flight of a coalesced request ends after the recording was saved.
+async fn respond(
+    config_a: Arc<Mutex<Configuration>>,
+    destination: storage::Destination,
+    response: ResourceData,
+    mut request: RequestData,
+    flight: Option<coalesce::Guard>,
+) -> Result<Response<Body>, Infallible> {
+    let (build_mode, recording, redaction) = {
+        let config = config_a.lock().await;
+        (
+            config.build_mode.clone(),
+            config.recording.clone().unwrap_or_default(),
+            config.redaction.clone().unwrap_or_default(),
+        )
     };
-    if response.code != 404 && build_mode == &BuildMode::Write {
-        storage::save(
+    if !matches!(build_mode, Some(BuildMode::Write | BuildMode::Refresh))
+        || !filter::is_recorded(
+            &recording,
+            &destination.uri,
             &response.method,
-            uri,
-            Some(Metadata {
-                code: response.code,
-                header: response.headers.clone(),
-            }),
-            body.clone(),
-            config_a,
+            response.code,
+            &response.headers,
         )
-        .await
-        .unwrap();
+    {
+        return get_response(response.headers, response.code, response.payload);
     }
 
-    get_response(response.headers, response.code, Body::from(body))
+    let (sender, body) = Body::channel();
+    let mut metadata = Metadata {
+        code: response.code,
+        header: response.headers.clone(),
+    };
+    headers::remove_framing(&mut metadata.header);
+    redact::redact_headers(&redaction, &mut metadata.header);
+    redact::redact_headers(&redaction, &mut request.request.header);
+    if let Some(body) = redact::redact_body(&redaction, &request.body) {
+        request.body = Bytes::from(body);
+    }
+    tokio::spawn(record(
+        destination,
+        metadata,
+        request,
+        response.payload,
+        sender,
+        config_a,
+        flight,
+    ));
+
+    get_response(response.headers, response.code, body)
+}
+
+/// Forwards the body to the client while it is written to the filesystem. The route is only
+/// added to the configuration after the whole body was received.
+async fn record(
+    destination: storage::Destination,
+    mut metadata: Metadata,
+    request: RequestData,
+    mut payload: Body,
+    mut sender: body::Sender,
+    config: Arc<Mutex<Configuration>>,
+    _flight: Option<coalesce::Guard>,
+) {
+    let _pending = persist::PendingRecording::start();
+    let (max_size, redaction) = {
+        let config = config.lock().await;
+        (
+            config.recording.as_ref().and_then(|r| r.max_size),
+            config.redaction.clone().unwrap_or_default(),
+        )
+    };
+    let (location, mut file) = match storage::create_temp_file(&destination.folder).await {
+        Ok(temp) => temp,
+        Err(e) => {
+            tracing::error!("Unable to create file for recording: {}", e);
+            forward(payload, sender).await;
+            return;
+        }
+    };
+
+    let mut client_connected = true;
+    let mut size = 0;
+    while let Some(data) = payload.data().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("Unable to read response from endpoint: {}", e);
+                sender.abort();
+                storage::remove_temp_file(&location).await;
+                return;
+            }
+        };
+
+        size += data.len() as u64;
+        if max_size.map(|m| size > m).unwrap_or(false) {
+            tracing::info!(
+                "Not recording {} because the body is too large",
+                destination.uri
+            );
+            storage::remove_temp_file(&location).await;
+            if client_connected && sender.send_data(data).await.is_ok() {
+                forward(payload, sender).await;
+            }
+            return;
+        }
+
+        if let Err(e) = file.write_all(&data).await {
+            tracing::error!("Unable to write recording: {}", e);
+            storage::remove_temp_file(&location).await;
+            if client_connected {
+                forward(payload, sender).await;
+            }
+            return;
+        }
+
+        if client_connected && sender.send_data(data).await.is_err() {
+            tracing::trace!("Client closed the connection. Continue recording");
+            client_connected = false;
+        }
+    }
+    drop(sender);
+
+    if let Err(e) = storage::sync_file(&mut file).await {
+        tracing::error!("Unable to write recording: {}", e);
+        storage::remove_temp_file(&location).await;
+        return;
+    }
+    drop(file);
+
+    match redact::redact_file(&redaction, &location).await {
+        // The recorded length does not match the redacted body
+        Ok(true) => {
+            metadata.header.remove(header::CONTENT_LENGTH);
+        }
+        Ok(false) => (),
+        Err(e) => {
+            tracing::error!("Unable to redact recording: {}", e);
+            storage::remove_temp_file(&location).await;
+            return;
+        }
+    }
+
+    let method = request.request.method.clone();
+    if let Err(e) = storage::save(
+        &method,
+        &destination,
+        Some(metadata),
+        request,
+        &location,
+        config,
+    )
+    .await
+    {
+        tracing::error!("Unable to save recording: {}", e);
+    }
+}
+
+/// Forwards the body to the client without recording it.
+async fn forward(mut payload: Body, mut sender: body::Sender) {
+    while let Some(data) = payload.data().await {
+        let Ok(data) = data else {
+            sender.abort();
+            return;
+        };
+        if sender.send_data(data).await.is_err() {
+            return;
+        }
+    }
 }
 
 /// Returns a respinse with headers and a code
 pub fn get_response(
-    headers: HeaderMap,
+    mut headers: HeaderMap,
     code: u16,
     body: Body,
 ) -> Result<Response<Body>, Infallible> {
-    let mut response = Response::builder().status(code);
-
-    for (key, value) in headers.into_iter() {
-        if let Some(key) = key {
-            response = response.header(key, value);
-        }
-    }
+    let mut response = Response::builder().status(code).body(body).unwrap();
+    headers::remove_hop_by_hop(&mut headers);
+    *response.headers_mut() = headers;
 
-    Ok(response.body(body).unwrap())
+    Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
-    use hyper::{Body, HeaderMap};
+    use std::sync::Arc;
+
+    use hyper::{
+        body::{self, Bytes},
+        header, Body, HeaderMap,
+    };
+    use tokio::sync::Mutex;
+
+    use crate::{
+        builder::{persist, request},
+        configuration::{
+            BuildMode, Configuration, Metadata, RecordedRequest, Recording, Route, RouteMethod,
+            UpstreamSettings,
+        },
+    };
+
+    use super::{record, respond, storage, RequestData, ResourceData};
+
+    /// A folder for the recordings of one test
+    fn temp_folder() -> String {
+        std::env::temp_dir()
+            .join(format!("moxy-{:016x}", rand::random::<u64>()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn destination(folder: &str, uri: &str) -> storage::Destination {
+        storage::Destination {
+            folder: folder.to_owned(),
+            ..storage::Destination::new(uri)
+        }
+    }
+
+    fn temp_files(folder: &str) -> usize {
+        std::fs::read_dir(format!("{folder}/.tmp"))
+            .map(|d| d.count())
+            .unwrap_or(0)
+    }
+
+    fn request_data(uri: &str) -> RequestData {
+        RequestData {
+            request: RecordedRequest {
+                method: RouteMethod::GET,
+                url: format!("http://localhost{uri}"),
+                header: HeaderMap::new(),
+                body: None,
+            },
+            body: Bytes::new(),
+        }
+    }
 
-    use crate::{builder::request, configuration::RouteMethod};
+    #[tokio::test]
+    async fn record_should_remove_the_recording_on_upstream_errors() {
+        let folder = temp_folder();
+        let config = Arc::new(Mutex::new(Configuration::default()));
+        let (mut upstream, payload) = Body::channel();
+        let (sender, client) = Body::channel();
+
+        let recording = tokio::spawn(record(
+            destination(&folder, "/record/error"),
+            Metadata::default(),
+            request_data("/record/error"),
+            payload,
+            sender,
+            config.clone(),
+            None,
+        ));
+        tokio::spawn(async move {
+            upstream.send_data(Bytes::from("part")).await.unwrap();
+            upstream.abort();
+        });
+
+        assert!(body::to_bytes(client).await.is_err());
+        recording.await.unwrap();
+        let files = temp_files(&folder);
+        let _ = tokio::fs::remove_dir_all(&folder).await;
+        assert_eq!(files, 0);
+        assert!(config.lock().await.routes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn record_should_forward_the_body_when_it_is_too_large() {
+        let folder = temp_folder();
+        let config = Arc::new(Mutex::new(Configuration {
+            recording: Some(Recording {
+                max_size: Some(4),
+                ..Recording::default()
+            }),
+            ..Configuration::default()
+        }));
+        let (mut upstream, payload) = Body::channel();
+        let (sender, client) = Body::channel();
+
+        let recording = tokio::spawn(record(
+            destination(&folder, "/record/large"),
+            Metadata::default(),
+            request_data("/record/large"),
+            payload,
+            sender,
+            config.clone(),
+            None,
+        ));
+        tokio::spawn(async move {
+            upstream.send_data(Bytes::from("abc")).await.unwrap();
+            upstream.send_data(Bytes::from("defg")).await.unwrap();
+        });
+
+        assert_eq!(body::to_bytes(client).await.unwrap(), "abcdefg");
+        recording.await.unwrap();
+        let files = temp_files(&folder);
+        let _ = tokio::fs::remove_dir_all(&folder).await;
+        assert_eq!(files, 0);
+        assert!(config.lock().await.routes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn record_should_continue_when_the_client_disconnects() {
+        let folder = temp_folder();
+        let config = Arc::new(Mutex::new(Configuration::default()));
+        let (mut upstream, payload) = Body::channel();
+        let (sender, client) = Body::channel();
+        drop(client);
+
+        let recording = tokio::spawn(record(
+            destination(&folder, "/record/disconnect"),
+            Metadata::default(),
+            request_data("/record/disconnect"),
+            payload,
+            sender,
+            config.clone(),
+            None,
+        ));
+        upstream.send_data(Bytes::from("recorded")).await.unwrap();
+        drop(upstream);
+        recording.await.unwrap();
+
+        let resource = config.lock().await.routes[0].resource.clone().unwrap();
+        let content = tokio::fs::read_to_string(&resource).await;
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+        assert_eq!(resource, format!("{folder}/record/disconnect.txt"));
+        assert_eq!(content.unwrap(), "recorded");
+    }
+
+    #[tokio::test]
+    async fn record_should_update_the_route_that_matched_a_refresh() {
+        let folder = temp_folder();
+        let route = Route {
+            method: RouteMethod::GET,
+            metadata: None,
+            path: "/record/$$$".to_string(),
+            resource: Some(format!("{folder}/record/$$$.txt")),
+            body: None,
+            messages: vec![],
+            faults: vec![],
+            request: None,
+            throttle: None,
+        };
+        let config = Arc::new(Mutex::new(Configuration {
+            routes: vec![route.clone()],
+            ..Configuration::default()
+        }));
+        let (sender, _client) = Body::channel();
+        let destination = storage::Destination {
+            route: Some(storage::RefreshedRoute {
+                path: route.path.clone(),
+                parameter: Some("5".to_string()),
+            }),
+            ..destination(&folder, "/record/5")
+        };
+
+        let request = RequestData {
+            body: Bytes::from("query"),
+            ..request_data("/record/5")
+        };
+
+        record(
+            destination,
+            Metadata::default(),
+            request,
+            Body::from("refreshed"),
+            sender,
+            config.clone(),
+            None,
+        )
+        .await;
+
+        let routes = config.lock().await.routes.clone();
+        let content = tokio::fs::read_to_string(format!("{folder}/record/5.txt")).await;
+        let sidecar = std::path::Path::new(&format!("{folder}/record/5.txt.request")).exists();
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].resource, route.resource);
+        assert!(routes[0].request.is_none());
+        assert_eq!(content.unwrap(), "refreshed");
+        assert!(!sidecar);
+    }
+
+    #[tokio::test]
+    async fn respond_should_record_the_masked_request() {
+        let folder = temp_folder();
+        let config = Arc::new(Mutex::new(Configuration {
+            build_mode: Some(BuildMode::Write),
+            ..Configuration::default()
+        }));
+        let mut request = request_data("/record/request");
+        request.request.method = RouteMethod::POST;
+        request
+            .request
+            .header
+            .insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        request.body = Bytes::from("query");
+        let response = ResourceData {
+            method: RouteMethod::POST,
+            headers: HeaderMap::new(),
+            code: 200,
+            payload: Body::from("response"),
+        };
+
+        let response = respond(
+            config.clone(),
+            destination(&folder, "/record/request"),
+            response,
+            request,
+            None,
+        )
+        .await
+        .unwrap();
+        body::to_bytes(response.into_body()).await.unwrap();
+        persist::wait_for_recordings().await;
+
+        let route = config.lock().await.routes[0].clone();
+        let recorded = route.request.unwrap();
+        let sidecar = tokio::fs::read_to_string(recorded.body.clone().unwrap()).await;
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+        assert_eq!(recorded.method, RouteMethod::POST);
+        assert_eq!(recorded.header[header::AUTHORIZATION], "REDACTED");
+        assert_eq!(
+            recorded.body,
+            Some(format!("{folder}/record/request.txt.request"))
+        );
+        assert_eq!(sidecar.unwrap(), "query");
+    }
 
     #[tokio::test]
     async fn request_no_body() {
         let _response = request::http::fetch_http(
             RouteMethod::GET,
             "http://example.com".to_string(),
-            Body::empty(),
+            Bytes::new(),
             HeaderMap::new(),
-            false
+            false,
+            &UpstreamSettings::default(),
         )
         .await
         .unwrap();
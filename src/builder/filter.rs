@@ -0,0 +1,263 @@
+use hyper::{header, HeaderMap};
+
+use crate::configuration::{Recording, RecordingRule, RouteMethod};
+
+/// Checks if a response should be recorded. The size of the body is checked while it is
+/// recorded.
+pub fn is_recorded(
+    recording: &Recording,
+    uri: &str,
+    method: &RouteMethod,
+    code: u16,
+    headers: &HeaderMap,
+) -> bool {
+    let path = uri.split(['?', '#']).next().unwrap_or_default();
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or_default();
+    if !is_status_recorded(recording, code) {
+        tracing::info!(
+            "Not recording {} because the status {} is not in the recording policy",
+            uri,
+            code
+        );
+        return false;
+    }
+    let matches = |rule: &RecordingRule| rule_matches(rule, path, method, code, content_type);
+
+    if !recording.include.is_empty() && !recording.include.iter().any(matches) {
+        tracing::info!("Not recording {} because it is not included", uri);
+        return false;
+    }
+    if recording.exclude.iter().any(matches) {
+        tracing::info!("Not recording {} because it is excluded", uri);
+        return false;
+    }
+    if let Some(max_size) = recording.max_size {
+        let length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok());
+        if length.map(|l| l > max_size).unwrap_or(false) {
+            tracing::info!("Not recording {} because the body is too large", uri);
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Checks if the status code is allowed by the recording policy.
+pub fn is_status_recorded(recording: &Recording, code: u16) -> bool {
+    recording.status.iter().any(|s| status_matches(s, code))
+}
+
+fn status_matches(pattern: &str, code: u16) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    if let Some(class) = pattern.strip_suffix("xx") {
+        return class
+            .parse::<u16>()
+            .map(|c| code / 100 == c)
+            .unwrap_or(false);
+    }
+    if let Some((from, to)) = pattern.split_once('-') {
+        return match (from.trim().parse::<u16>(), to.trim().parse::<u16>()) {
+            (Ok(from), Ok(to)) => (from..=to).contains(&code),
+            _ => false,
+        };
+    }
+
+    pattern.parse::<u16>().map(|c| c == code).unwrap_or(false)
+}
+
+fn rule_matches(
+    rule: &RecordingRule,
+    path: &str,
+    method: &RouteMethod,
+    code: u16,
+    content_type: &str,
+) -> bool {
+    rule.path
+        .as_ref()
+        .map(|p| glob_matches(p.as_bytes(), path.as_bytes()))
+        .unwrap_or(true)
+        && rule.method.as_ref().map(|m| m == method).unwrap_or(true)
+        && (rule.status.is_empty() || rule.status.contains(&code))
+        && rule
+            .content_type
+            .as_ref()
+            .map(|c| content_type.to_lowercase().starts_with(&c.to_lowercase()))
+            .unwrap_or(true)
+}
+
+/// Matches a path against a glob. `**` matches any characters, `*` and `?` do not match `/`.
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(path.first(), Some(c) if *c != b'/') && glob_matches(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header, HeaderMap};
+
+    use crate::configuration::{Recording, RecordingRule, RouteMethod};
+
+    use super::{glob_matches, is_recorded, status_matches};
+
+    fn headers(content_type: &str, length: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        headers.insert(header::CONTENT_LENGTH, length.into());
+        headers
+    }
+
+    #[test]
+    fn glob_matches_should_support_wildcards() {
+        assert!(glob_matches(b"/favicon.ico", b"/favicon.ico"));
+        assert!(glob_matches(b"/api/*/health", b"/api/users/health"));
+        assert!(!glob_matches(b"/api/*/health", b"/api/users/v1/health"));
+        assert!(glob_matches(b"/api/**/health", b"/api/users/v1/health"));
+        assert!(glob_matches(b"/**.png", b"/assets/images/a.png"));
+        assert!(glob_matches(b"/v?/a", b"/v1/a"));
+        assert!(!glob_matches(b"/v?/a", b"/v10/a"));
+    }
+
+    #[test]
+    fn status_matches_should_support_classes_and_ranges() {
+        assert!(status_matches("2xx", 204));
+        assert!(!status_matches("2xx", 301));
+        assert!(status_matches("400-403", 401));
+        assert!(!status_matches("400-403", 404));
+        assert!(status_matches("301", 301));
+        assert!(!status_matches("abc", 200));
+    }
+
+    #[test]
+    fn is_recorded_should_skip_errors_by_default() {
+        let recording = Recording::default();
+        let headers = headers("text/plain", 10);
+
+        assert!(is_recorded(
+            &recording,
+            "/a",
+            &RouteMethod::GET,
+            200,
+            &headers
+        ));
+        assert!(is_recorded(
+            &recording,
+            "/a",
+            &RouteMethod::GET,
+            302,
+            &headers
+        ));
+        assert!(!is_recorded(
+            &recording,
+            "/a",
+            &RouteMethod::GET,
+            404,
+            &headers
+        ));
+        assert!(!is_recorded(
+            &recording,
+            "/a",
+            &RouteMethod::GET,
+            500,
+            &headers
+        ));
+    }
+
+    #[test]
+    fn is_recorded_should_apply_exclude_rules() {
+        let recording = Recording {
+            exclude: vec![
+                RecordingRule {
+                    path: Some("/favicon.ico".to_string()),
+                    ..RecordingRule::default()
+                },
+                RecordingRule {
+                    method: Some(RouteMethod::POST),
+                    path: Some("/analytics/**".to_string()),
+                    ..RecordingRule::default()
+                },
+            ],
+            ..Recording::default()
+        };
+        let headers = headers("text/plain", 10);
+
+        assert!(!is_recorded(
+            &recording,
+            "/favicon.ico",
+            &RouteMethod::GET,
+            200,
+            &headers
+        ));
+        assert!(!is_recorded(
+            &recording,
+            "/analytics/v1/beacon?id=1",
+            &RouteMethod::POST,
+            200,
+            &headers
+        ));
+        assert!(is_recorded(
+            &recording,
+            "/analytics/v1/beacon",
+            &RouteMethod::GET,
+            200,
+            &headers
+        ));
+    }
+
+    #[test]
+    fn is_recorded_should_require_include_rules_and_size() {
+        let recording = Recording {
+            include: vec![RecordingRule {
+                content_type: Some("application/json".to_string()),
+                status: vec![200, 201],
+                ..RecordingRule::default()
+            }],
+            max_size: Some(100),
+            ..Recording::default()
+        };
+
+        assert!(is_recorded(
+            &recording,
+            "/api",
+            &RouteMethod::GET,
+            200,
+            &headers("application/json; charset=utf-8", 10)
+        ));
+        assert!(!is_recorded(
+            &recording,
+            "/api",
+            &RouteMethod::GET,
+            500,
+            &headers("application/json", 10)
+        ));
+        assert!(!is_recorded(
+            &recording,
+            "/a.png",
+            &RouteMethod::GET,
+            200,
+            &headers("image/png", 10)
+        ));
+        assert!(!is_recorded(
+            &recording,
+            "/api",
+            &RouteMethod::GET,
+            200,
+            &headers("application/json", 1000)
+        ));
+    }
+}
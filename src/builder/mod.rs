@@ -1,8 +1,16 @@
 //! This contains the logic to modify the configuration an filesystem inorder to support
 //! `"build_mode": "write"`
 
+/// This contains the merging of concurrent requests to the same route.
+pub mod coalesce;
 /// This contains the main builder functionality. That is called by the router.
 pub mod core;
+/// This contains the filters that decide which responses are recorded.
+pub mod filter;
+/// This contains the batched saving of the configuration.
+pub mod persist;
+/// This contains the redaction of secrets in recordings.
+pub mod redact;
 /// This contains the logic off feching new data.
 pub mod request;
 /// This contains how new data is saved.
@@ -0,0 +1,203 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{Mutex, Notify};
+
+use crate::configuration::{self, Configuration};
+
+/// Changes within this time after the first change are saved together.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Collects the changes of the configuration and saves them together.
+struct Writer {
+    /// True when the configuration has changes that are not saved
+    dirty: AtomicBool,
+    changed: Notify,
+    /// Only one save runs at a time, so that an older configuration never replaces a newer one.
+    saving: Mutex<()>,
+}
+
+fn writer() -> &'static Writer {
+    static WRITER: OnceLock<Writer> = OnceLock::new();
+    WRITER.get_or_init(Writer::new)
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self {
+            dirty: AtomicBool::new(false),
+            changed: Notify::new(),
+            saving: Mutex::new(()),
+        }
+    }
+
+    fn mark_changed(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    /// Waits for changes and saves them with `save` after `DEBOUNCE`.
+    async fn run<F, R>(&self, config: Arc<Mutex<Configuration>>, save: F)
+    where
+        F: Fn(Configuration) -> R,
+        R: Future<Output = Result<(), std::io::Error>>,
+    {
+        loop {
+            self.changed.notified().await;
+            tokio::time::sleep(DEBOUNCE).await;
+            if let Err(e) = self.flush(config.clone(), &save).await {
+                tracing::error!("Unable to save configuration: {}", e);
+            }
+        }
+    }
+
+    /// Saves the configuration with `save` when it has unsaved changes.
+    async fn flush<F, R>(
+        &self,
+        config: Arc<Mutex<Configuration>>,
+        save: F,
+    ) -> Result<(), std::io::Error>
+    where
+        F: FnOnce(Configuration) -> R,
+        R: Future<Output = Result<(), std::io::Error>>,
+    {
+        let _saving = self.saving.lock().await;
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let config = config.lock().await.to_owned();
+        tracing::trace!("Save configuration with {} routes", config.routes.len());
+        if let Err(e) = save(config).await {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of recordings that are not saved yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn recorded() -> &'static Notify {
+    static RECORDED: OnceLock<Notify> = OnceLock::new();
+    RECORDED.get_or_init(Notify::new)
+}
+
+/// A recording that is not saved yet. `wait_for_recordings` waits until it is dropped.
+pub struct PendingRecording(());
+
+impl PendingRecording {
+    /// Marks the start of a recording.
+    pub fn start() -> Self {
+        PENDING.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for PendingRecording {
+    fn drop(&mut self) {
+        if PENDING.fetch_sub(1, Ordering::SeqCst) == 1 {
+            recorded().notify_waiters();
+        }
+    }
+}
+
+/// Waits until all running recordings are saved or dropped.
+pub async fn wait_for_recordings() {
+    loop {
+        let notified = recorded().notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if PENDING.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Marks the configuration as changed. It is saved by the writer that is started with
+/// `start_writer`. The change has to be made to the shared configuration before.
+pub fn mark_changed() {
+    writer().mark_changed();
+}
+
+/// Saves the changes of the configuration in the background. Changes that are marked shortly
+/// after each other are written with one save.
+pub fn start_writer(config: Arc<Mutex<Configuration>>) {
+    tokio::spawn(writer().run(config, configuration::save_configuration));
+}
+
+/// Saves the configuration now when it has unsaved changes.
+pub async fn flush(config: Arc<Mutex<Configuration>>) -> Result<(), std::io::Error> {
+    writer()
+        .flush(config, configuration::save_configuration)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::sync::Mutex;
+
+    use crate::configuration::Configuration;
+
+    use super::{Writer, DEBOUNCE};
+
+    #[tokio::test]
+    async fn run_should_save_changes_together() {
+        let writer = Arc::new(Writer::new());
+        let saves = Arc::new(AtomicUsize::new(0));
+        let config = Arc::new(Mutex::new(Configuration::default()));
+
+        let running = {
+            let writer = writer.clone();
+            let saves = saves.clone();
+            tokio::spawn(async move {
+                writer
+                    .run(config, |_| {
+                        saves.fetch_add(1, Ordering::SeqCst);
+                        async { Ok(()) }
+                    })
+                    .await
+            })
+        };
+        for _ in 0..3 {
+            writer.mark_changed();
+        }
+        tokio::time::sleep(DEBOUNCE * 3).await;
+        running.abort();
+
+        assert_eq!(saves.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn flush_should_only_save_changes() {
+        let writer = Writer::new();
+        let saves = AtomicUsize::new(0);
+        let config = Arc::new(Mutex::new(Configuration::default()));
+        let save = |_| {
+            saves.fetch_add(1, Ordering::SeqCst);
+            async { Ok(()) }
+        };
+
+        writer.flush(config.clone(), save).await.unwrap();
+        assert_eq!(saves.load(Ordering::SeqCst), 0);
+
+        writer.mark_changed();
+        writer.flush(config.clone(), save).await.unwrap();
+        writer.flush(config, save).await.unwrap();
+        assert_eq!(saves.load(Ordering::SeqCst), 1);
+    }
+}
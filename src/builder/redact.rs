@@ -0,0 +1,448 @@
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use regex::Regex;
+use serde_json::Value;
+use tokio::fs;
+
+use crate::configuration::{self, Redaction};
+
+use super::storage;
+
+const DEFAULT_REPLACEMENT: &str = "REDACTED";
+
+/// Removes and masks the configured headers.
+pub fn redact_headers(redaction: &Redaction, headers: &mut HeaderMap) {
+    for name in redaction
+        .drop_headers
+        .iter()
+        .map(String::as_str)
+        .filter_map(get_header_name)
+    {
+        headers.remove(name);
+    }
+
+    let replacement = match HeaderValue::from_str(get_replacement(redaction)) {
+        Ok(replacement) => replacement,
+        Err(_) => {
+            tracing::error!("The replacement is not a valid header value, using the default");
+            HeaderValue::from_static(DEFAULT_REPLACEMENT)
+        }
+    };
+    for name in redaction
+        .mask_headers
+        .iter()
+        .map(String::as_str)
+        .filter_map(get_header_name)
+    {
+        if headers.contains_key(&name) {
+            headers.insert(name, replacement.clone());
+        }
+    }
+}
+
+/// Applies the body rules. Returns nothing when the body was not changed.
+pub fn redact_body(redaction: &Redaction, body: &[u8]) -> Option<Vec<u8>> {
+    if redaction.body.is_empty() {
+        return None;
+    }
+    let replacement = get_replacement(redaction);
+    let mut changed = false;
+    let mut body = body.to_vec();
+
+    let json_paths: Vec<Vec<Segment>> = redaction
+        .body
+        .iter()
+        .filter_map(|r| r.json_path.as_deref())
+        .filter_map(|p| {
+            let path = parse_json_path(p);
+            if path.is_none() {
+                tracing::error!("Invalid json path for redaction: {}", p);
+            }
+            path
+        })
+        .collect();
+    if !json_paths.is_empty() {
+        if let Ok(mut json) = serde_json::from_slice::<Value>(&body) {
+            for path in &json_paths {
+                changed |= redact_json(&mut json, path, replacement);
+            }
+            if changed {
+                body = serde_json::to_vec(&json).unwrap();
+            }
+        }
+    }
+
+    for pattern in redaction.body.iter().filter_map(|r| r.regex.as_deref()) {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                tracing::error!("Invalid regex for redaction {}: {}", pattern, e);
+                continue;
+            }
+        };
+        let Ok(text) = std::str::from_utf8(&body) else {
+            break;
+        };
+        if regex.is_match(text) {
+            body = regex
+                .replace_all(text, regex::NoExpand(replacement))
+                .into_owned()
+                .into_bytes();
+            changed = true;
+        }
+    }
+
+    changed.then_some(body)
+}
+
+/// Applies the body rules to a file. Returns true when the file was changed.
+pub async fn redact_file(redaction: &Redaction, location: &str) -> Result<bool, std::io::Error> {
+    if redaction.body.is_empty() {
+        return Ok(false);
+    }
+    let body = fs::read(location).await?;
+    let Some(body) = redact_body(redaction, &body) else {
+        return Ok(false);
+    };
+
+    storage::write_atomic(location, &body).await?;
+
+    Ok(true)
+}
+
+/// Applies the redaction rules to all existing recordings. This is called by `moxy scrub`.
+pub async fn scrub(mut config: configuration::Configuration) -> Result<(), std::io::Error> {
+    // The default rules mask credentials, so they are also scrubbed without a configuration
+    let redaction = config.redaction.clone().unwrap_or_default();
+
+    for route in config.routes.iter_mut() {
+        if let Some(metadata) = route.metadata.as_mut() {
+            redact_headers(&redaction, &mut metadata.header);
+        }
+        if let Some(resource) = route.resource.as_deref() {
+            let mut changed = false;
+            for location in get_resource_files(resource).await {
+                changed |= scrub_file(&redaction, &location).await;
+            }
+            if changed {
+                if let Some(metadata) = route.metadata.as_mut() {
+                    metadata.header.remove(hyper::header::CONTENT_LENGTH);
+                }
+            }
+        }
+        if let Some(request) = route.request.as_mut() {
+            redact_headers(&redaction, &mut request.header);
+            if let Some(body) = &request.body {
+                scrub_file(&redaction, body).await;
+            }
+        }
+        for message in &route.messages {
+            scrub_file(&redaction, &message.location).await;
+        }
+    }
+
+    configuration::save_configuration(config).await
+}
+
+/// Returns the recorded files of a resource. The `$$$` of a parameterized route matches the
+/// files of all parameters that were recorded.
+async fn get_resource_files(resource: &str) -> Vec<String> {
+    let Some((prefix, suffix)) = resource.split_once("$$$") else {
+        return vec![resource.to_owned()];
+    };
+
+    let folder = prefix.rfind('/').map(|i| &prefix[..i]).unwrap_or(".");
+    let mut folders = vec![folder.to_owned()];
+    let mut files = vec![];
+    while let Some(folder) = folders.pop() {
+        let Ok(mut entries) = fs::read_dir(&folder).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let location = entry.path().to_string_lossy().into_owned();
+            if entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false) {
+                folders.push(location);
+            } else if location.len() > prefix.len() + suffix.len()
+                && location.starts_with(prefix)
+                && location.ends_with(suffix)
+            {
+                files.push(location);
+            }
+        }
+    }
+    files.sort();
+
+    files
+}
+
+async fn scrub_file(redaction: &Redaction, location: &str) -> bool {
+    match redact_file(redaction, location).await {
+        Ok(changed) => {
+            if changed {
+                tracing::info!("Redacted {}", location);
+            }
+            changed
+        }
+        Err(e) => {
+            tracing::error!("Unable to redact {}: {}", location, e);
+            false
+        }
+    }
+}
+
+fn get_replacement(redaction: &Redaction) -> &str {
+    redaction
+        .replacement
+        .as_deref()
+        .unwrap_or(DEFAULT_REPLACEMENT)
+}
+
+fn get_header_name(name: &str) -> Option<HeaderName> {
+    HeaderName::from_bytes(name.to_lowercase().as_bytes()).ok()
+}
+
+/// Part of a JSONPath
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    /// `.name` or `['name']`
+    Child(String),
+    /// `[0]`
+    Index(usize),
+    /// `.*` or `[*]`
+    Wildcard,
+    /// `..name`
+    Descendant(String),
+}
+
+/// Parses the supported subset of JSONPath.
+fn parse_json_path(path: &str) -> Option<Vec<Segment>> {
+    let mut rest = path.trim().strip_prefix('$')?;
+    let mut segments = vec![];
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(Segment::Descendant(r[..end].to_owned()));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            segments.push(match &r[..end] {
+                "" => return None,
+                "*" => Segment::Wildcard,
+                name => Segment::Child(name.to_owned()),
+            });
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let end = r.find(']')?;
+            let selector = r[..end].trim();
+            segments.push(if selector == "*" {
+                Segment::Wildcard
+            } else if let Ok(index) = selector.parse::<usize>() {
+                Segment::Index(index)
+            } else {
+                let name = selector
+                    .strip_prefix('\'')
+                    .and_then(|s| s.strip_suffix('\''))
+                    .or_else(|| selector.strip_prefix('"').and_then(|s| s.strip_suffix('"')))?;
+                Segment::Child(name.to_owned())
+            });
+            rest = &r[end + 1..];
+        } else {
+            return None;
+        }
+    }
+
+    Some(segments)
+}
+
+/// Replaces all values that match the path. Returns true when something was replaced.
+fn redact_json(value: &mut Value, path: &[Segment], replacement: &str) -> bool {
+    let Some((segment, rest)) = path.split_first() else {
+        *value = Value::String(replacement.to_owned());
+        return true;
+    };
+
+    match segment {
+        Segment::Child(name) => value
+            .get_mut(name.as_str())
+            .map(|v| redact_json(v, rest, replacement))
+            .unwrap_or(false),
+        Segment::Index(index) => value
+            .get_mut(*index)
+            .map(|v| redact_json(v, rest, replacement))
+            .unwrap_or(false),
+        Segment::Wildcard => {
+            let mut changed = false;
+            for child in get_children(value) {
+                changed |= redact_json(child, rest, replacement);
+            }
+            changed
+        }
+        Segment::Descendant(name) => {
+            let mut changed = false;
+            if let Some(v) = value.get_mut(name.as_str()) {
+                changed |= redact_json(v, rest, replacement);
+            }
+            for child in get_children(value) {
+                changed |= redact_json(child, path, replacement);
+            }
+            changed
+        }
+    }
+}
+
+fn get_children(value: &mut Value) -> Vec<&mut Value> {
+    match value {
+        Value::Array(values) => values.iter_mut().collect(),
+        Value::Object(values) => values.values_mut().collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header, HeaderMap};
+
+    use crate::configuration::{BodyRedaction, Redaction};
+
+    use super::{get_resource_files, parse_json_path, redact_body, redact_headers, Segment};
+
+    fn redaction(json_path: Option<&str>, regex: Option<&str>) -> Redaction {
+        Redaction {
+            body: vec![BodyRedaction {
+                json_path: json_path.map(|p| p.to_string()),
+                regex: regex.map(|r| r.to_string()),
+            }],
+            ..Redaction::default()
+        }
+    }
+
+    #[test]
+    fn redact_headers_should_mask_credentials_by_default() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+        headers.insert(header::COOKIE, "session=1".parse().unwrap());
+        headers.insert(header::SET_COOKIE, "session=2".parse().unwrap());
+        headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+
+        redact_headers(&serde_json::from_str("{}").unwrap(), &mut headers);
+
+        assert_eq!(headers[header::AUTHORIZATION], "REDACTED");
+        assert_eq!(headers[header::COOKIE], "REDACTED");
+        assert_eq!(headers[header::SET_COOKIE], "REDACTED");
+        assert_eq!(headers[header::ACCEPT], "*/*");
+    }
+
+    #[test]
+    fn redact_headers_should_fall_back_to_the_default_replacement() {
+        let redaction = Redaction {
+            replacement: Some("line\nbreak".to_string()),
+            ..Redaction::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+
+        redact_headers(&redaction, &mut headers);
+
+        assert_eq!(headers[header::AUTHORIZATION], "REDACTED");
+    }
+
+    #[tokio::test]
+    async fn get_resource_files_should_expand_parameters() {
+        let folder = std::env::temp_dir()
+            .join(format!("moxy-{:016x}", rand::random::<u64>()))
+            .to_string_lossy()
+            .into_owned();
+        for file in [
+            "users/1.json",
+            "users/2.json",
+            "users/2.json.request",
+            "other.json",
+        ] {
+            let location = format!("{folder}/{file}");
+            tokio::fs::create_dir_all(&location[..location.rfind('/').unwrap()])
+                .await
+                .unwrap();
+            tokio::fs::write(&location, "{}").await.unwrap();
+        }
+
+        let files = get_resource_files(&format!("{folder}/users/$$$.json")).await;
+        let single = get_resource_files(&format!("{folder}/other.json")).await;
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                format!("{folder}/users/1.json"),
+                format!("{folder}/users/2.json")
+            ]
+        );
+        assert_eq!(single, vec![format!("{folder}/other.json")]);
+    }
+
+    #[test]
+    fn parse_json_path_should_support_subset() {
+        assert_eq!(
+            parse_json_path("$.items[*].token"),
+            Some(vec![
+                Segment::Child("items".to_string()),
+                Segment::Wildcard,
+                Segment::Child("token".to_string())
+            ])
+        );
+        assert_eq!(
+            parse_json_path("$..password"),
+            Some(vec![Segment::Descendant("password".to_string())])
+        );
+        assert_eq!(
+            parse_json_path("$['user'][0]"),
+            Some(vec![Segment::Child("user".to_string()), Segment::Index(0)])
+        );
+        assert_eq!(parse_json_path("user.name"), None);
+    }
+
+    #[test]
+    fn redact_body_should_replace_json_values() {
+        let body = br#"{"user":{"email":"a@b.c","token":"x"},"items":[{"token":"y"}]}"#;
+
+        let body = redact_body(&redaction(Some("$..token"), None), body).unwrap();
+
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"{"items":[{"token":"REDACTED"}],"user":{"email":"a@b.c","token":"REDACTED"}}"#
+        );
+    }
+
+    #[test]
+    fn redact_body_should_replace_regex_matches() {
+        let redaction = redaction(None, Some(r"[\w.]+@[\w.]+"));
+
+        assert_eq!(
+            redact_body(&redaction, b"mail: a@b.c and d@e.f").unwrap(),
+            b"mail: REDACTED and REDACTED"
+        );
+        assert_eq!(redact_body(&redaction, b"nothing"), None);
+    }
+
+    #[test]
+    fn redact_headers_should_drop_and_mask() {
+        let redaction = Redaction {
+            drop_headers: vec!["Set-Cookie".to_string()],
+            mask_headers: vec!["authorization".to_string()],
+            ..Redaction::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SET_COOKIE, "session=1".parse().unwrap());
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+
+        redact_headers(&redaction, &mut headers);
+
+        assert!(headers.get(header::SET_COOKIE).is_none());
+        assert_eq!(headers[header::AUTHORIZATION], "REDACTED");
+    }
+}
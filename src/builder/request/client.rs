@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::configuration::{ClientCertificate, UpstreamSettings};
+
+use super::{proxy, tls};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Settings that can only be set when a client is built
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientSettings {
+    no_ssl_check: bool,
+    connect_timeout: Duration,
+    ca_certificates: Option<Vec<String>>,
+    client_certificate: Option<ClientCertificate>,
+    proxy: Option<String>,
+    no_proxy: Option<String>,
+}
+
+/// Clients are shared by all requests so that connections and TLS sessions are reused.
+fn clients() -> &'static Mutex<HashMap<ClientSettings, reqwest::Client>> {
+    static CLIENTS: OnceLock<Mutex<HashMap<ClientSettings, reqwest::Client>>> = OnceLock::new();
+    CLIENTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Returns the client for the settings. It is only built once. A client that can not be built
+/// is not cached.
+pub fn get_client(
+    no_ssl_check: bool,
+    settings: &UpstreamSettings,
+) -> Result<reqwest::Client, Error> {
+    let key = ClientSettings {
+        no_ssl_check,
+        connect_timeout: settings.get_connect_timeout(),
+        ca_certificates: settings.ca_certificates.clone(),
+        client_certificate: settings.client_certificate.clone(),
+        proxy: settings.proxy.clone(),
+        no_proxy: settings.no_proxy.clone(),
+    };
+
+    let mut clients = clients().lock().unwrap();
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+
+    tracing::trace!("Build client: {:?}", key);
+    let mut builder = reqwest::Client::builder().connect_timeout(key.connect_timeout);
+    let connector = tls::get_tls_connector(no_ssl_check, settings)
+        .map_err(|e| format!("Unable to create tls connector: {e}"))?;
+    builder = builder.use_preconfigured_tls(connector);
+    let proxy_settings = settings.clone();
+    builder = builder.proxy(reqwest::Proxy::custom(move |url| {
+        proxy::get_proxy(&proxy_settings, url)
+    }));
+
+    let client = builder.build()?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::UpstreamSettings;
+
+    use super::{clients, get_client};
+
+    #[test]
+    fn get_client_should_fail_without_ca_certificate() {
+        let settings = UpstreamSettings {
+            ca_certificates: Some(vec!["./missing-ca.pem".to_owned()]),
+            ..Default::default()
+        };
+
+        assert!(get_client(false, &settings).is_err());
+        assert!(!clients()
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|key| key.ca_certificates == settings.ca_certificates));
+    }
+}
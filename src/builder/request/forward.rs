@@ -0,0 +1,157 @@
+use std::net::SocketAddr;
+
+use hyper::{header, header::HeaderValue, HeaderMap, Uri};
+
+use crate::configuration::Forwarding;
+
+/// Adds the `x-forwarded-*` and `forwarded` headers that describe the client.
+pub fn add_forwarded_headers(
+    forwarding: &Forwarding,
+    headers: &mut HeaderMap,
+    client: Option<SocketAddr>,
+) {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_owned());
+    let client = client.map(|c| c.ip());
+
+    if forwarding.x_forwarded {
+        if let Some(client) = client {
+            let forwarded_for = match headers
+                .get("x-forwarded-for")
+                .and_then(|f| f.to_str().ok())
+            {
+                Some(previous) => format!("{previous}, {client}"),
+                None => client.to_string(),
+            };
+            insert(headers, "x-forwarded-for", &forwarded_for);
+        }
+        insert(headers, "x-forwarded-proto", "http");
+        if let Some(host) = &host {
+            insert(headers, "x-forwarded-host", host);
+        }
+    }
+
+    if forwarding.forwarded {
+        let mut forwarded = vec![];
+        if let Some(client) = client {
+            forwarded.push(match client {
+                std::net::IpAddr::V4(ip) => format!("for={ip}"),
+                std::net::IpAddr::V6(ip) => format!("for=\"[{ip}]\""),
+            });
+        }
+        if let Some(host) = &host {
+            forwarded.push(format!("host=\"{host}\""));
+        }
+        forwarded.push(String::from("proto=http"));
+        let forwarded = forwarded.join(";");
+        let forwarded = match headers.get(header::FORWARDED).and_then(|f| f.to_str().ok()) {
+            Some(previous) => format!("{previous}, {forwarded}"),
+            None => forwarded,
+        };
+        insert(headers, header::FORWARDED.as_str(), &forwarded);
+    }
+}
+
+/// Removes the `host` of moxy so that the host of the remote is used. With `rewrite_origin`
+/// `origin` and `referer` point to the remote as well.
+pub fn rewrite_for_remote(forwarding: &Forwarding, headers: &mut HeaderMap, remote: &str) {
+    headers.remove(header::HOST);
+    if !forwarding.rewrite_origin {
+        return;
+    }
+    let Some(origin) = get_origin(remote) else {
+        tracing::error!("Unable to rewrite origin for remote: {}", remote);
+        return;
+    };
+
+    if headers.contains_key(header::ORIGIN) {
+        insert(headers, header::ORIGIN.as_str(), &origin);
+    }
+    let referer = headers
+        .get(header::REFERER)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| r.parse::<Uri>().ok());
+    if let Some(referer) = referer {
+        let path = referer
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        insert(headers, header::REFERER.as_str(), &(origin + path));
+    }
+}
+
+/// Returns scheme, host and port of a url
+fn get_origin(url: &str) -> Option<String> {
+    let uri = url.parse::<Uri>().ok()?;
+
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+fn insert(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(e) => tracing::error!("Invalid value for {}: {}", name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header, HeaderMap};
+
+    use crate::configuration::Forwarding;
+
+    use super::{add_forwarded_headers, rewrite_for_remote};
+
+    fn forwarding() -> Forwarding {
+        Forwarding {
+            x_forwarded: true,
+            forwarded: true,
+            rewrite_origin: true,
+        }
+    }
+
+    #[test]
+    fn add_forwarded_headers_should_describe_client() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "localhost:8080".parse().unwrap());
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+
+        add_forwarded_headers(
+            &forwarding(),
+            &mut headers,
+            Some("127.0.0.1:4000".parse().unwrap()),
+        );
+
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 127.0.0.1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "localhost:8080");
+        assert_eq!(
+            headers[header::FORWARDED],
+            "for=127.0.0.1;host=\"localhost:8080\";proto=http"
+        );
+    }
+
+    #[test]
+    fn rewrite_for_remote_should_use_remote_origin() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "localhost:8080".parse().unwrap());
+        headers.insert(header::ORIGIN, "http://localhost:8080".parse().unwrap());
+        headers.insert(
+            header::REFERER,
+            "http://localhost:8080/app/page?a=1".parse().unwrap(),
+        );
+
+        rewrite_for_remote(&forwarding(), &mut headers, "https://api.example.com");
+
+        assert!(headers.get(header::HOST).is_none());
+        assert_eq!(headers[header::ORIGIN], "https://api.example.com");
+        assert_eq!(
+            headers[header::REFERER],
+            "https://api.example.com/app/page?a=1"
+        );
+    }
+}
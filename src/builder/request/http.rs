@@ -1,51 +1,169 @@
-use hyper::{body::Bytes, HeaderMap};
-use reqwest::Error;
+use std::time::Duration;
 
-use crate::{builder::core::ResourceData, configuration::RouteMethod};
+use futures_util::{stream, Stream, StreamExt};
+use hyper::{body::Bytes, Body, HeaderMap, StatusCode};
 
-/// Load data from external http source
+use crate::{
+    builder::core::ResourceData,
+    configuration::{RouteMethod, UpstreamSettings},
+    response::headers,
+};
+
+use super::client;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Why the remote did not respond
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchError {
+    /// The remote could not be reached
+    Unreachable,
+    /// The remote did not respond in time
+    Timeout,
+}
+
+impl FetchError {
+    /// The status code that the client gets instead of a response of the remote
+    pub fn status(self) -> u16 {
+        match self {
+            FetchError::Unreachable => 502,
+            FetchError::Timeout => 504,
+        }
+    }
+}
+
+/// Load data from external http source. Requests with idempotent methods are retried when the
+/// remote is not reachable or not available. The error of the last attempt is returned when no
+/// attempt got a response.
 pub async fn fetch_http(
     method: RouteMethod,
-    url: impl reqwest::IntoUrl,
-    body: impl Into<reqwest::Body>,
+    url: String,
+    body: Bytes,
     header: HeaderMap,
     no_ssl_check: bool,
-) -> Option<ResourceData> {
-    let response = get_request(method.clone(), url, body, header, no_ssl_check).send().await;
+    settings: &UpstreamSettings,
+) -> Result<ResourceData, FetchError> {
+    let read_timeout = settings.get_read_timeout();
+    let retries = if is_idempotent(&method) {
+        settings.get_retries()
+    } else {
+        0
+    };
+    let mut backoff = settings.get_retry_backoff();
+    let mut error = FetchError::Unreachable;
+
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            tracing::info!("Retry {} of {} for {}", attempt, retries, url);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        let request = match get_request(
+            method.clone(),
+            &url,
+            body.clone(),
+            header.clone(),
+            no_ssl_check,
+            settings,
+        ) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("Unable to create the request to {}: {}", url, e);
+                return Err(FetchError::Unreachable);
+            }
+        };
+        let response = match tokio::time::timeout(read_timeout, request.send()).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                tracing::error!("Request to {} failed: {}", url, e);
+                error = if e.is_timeout() {
+                    FetchError::Timeout
+                } else {
+                    FetchError::Unreachable
+                };
+                continue;
+            }
+            Err(_) => {
+                tracing::error!("Request to {} timed out", url);
+                error = FetchError::Timeout;
+                continue;
+            }
+        };
+
+        if attempt < retries && is_unavailable(response.status()) {
+            tracing::error!("Remote {} responded with {}", url, response.status());
+            continue;
+        }
 
-    if let Ok(response) = response {
-        return Some(ResourceData {
+        return Ok(ResourceData {
             method,
             headers: response.headers().clone(),
             code: response.status().as_u16(),
-            payload: get_payload(response.bytes().await),
+            payload: Body::wrap_stream(with_read_timeout(response.bytes_stream(), read_timeout)),
         });
     }
 
-    None
+    Err(error)
 }
 
-/// Get request to Load data from external http source
+/// Get request to Load data from external http source. Fails when no client can be built for
+/// the settings.
 pub fn get_request(
     method: RouteMethod,
     url: impl reqwest::IntoUrl,
     body: impl Into<reqwest::Body>,
     header: HeaderMap,
     no_ssl_check: bool,
-) -> reqwest::RequestBuilder {
-    let client = reqwest::Client::builder()
-        .danger_accept_invalid_certs(no_ssl_check)
-        .build()
-        .unwrap_or_default();
+    settings: &UpstreamSettings,
+) -> Result<reqwest::RequestBuilder, Error> {
+    let client = client::get_client(no_ssl_check, settings)?;
     let mut req = client.request(method.to_owned().into(), url);
 
+    // Only ask for encodings that can be decoded, so that plain content is recorded
+    let mut header = header;
+    header.remove(hyper::header::ACCEPT_ENCODING);
+    headers::remove_framing(&mut header);
     req = req.headers(header);
     req = req.body(body);
 
-    req
+    Ok(req)
+}
+
+/// Ends the body with an error when the remote stops sending data.
+fn with_read_timeout(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    read_timeout: Duration,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static {
+    stream::unfold(Some(Box::pin(body)), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout(read_timeout, body.next()).await {
+            Ok(Some(data)) => Some((data.map_err(std::io::Error::other), Some(body))),
+            Ok(None) => None,
+            Err(_) => {
+                tracing::error!("Remote stopped sending the body");
+                let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "read timeout");
+                Some((Err(error), None))
+            }
+        }
+    })
+}
+
+fn is_idempotent(method: &RouteMethod) -> bool {
+    matches!(
+        method,
+        RouteMethod::GET
+            | RouteMethod::HEAD
+            | RouteMethod::PUT
+            | RouteMethod::DELETE
+            | RouteMethod::OPTIONS
+            | RouteMethod::TRACE
+    )
 }
 
-/// Convert request body to Vec<u8>
-fn get_payload(bytes: Result<Bytes, Error>) -> Option<Vec<u8>> {
-    bytes.map_or(None, |b| Some(b.to_vec()))
+fn is_unavailable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
 }
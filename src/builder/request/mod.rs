@@ -1,4 +1,12 @@
+/// shared clients for the remote
+pub mod client;
+/// headers that describe the client and the remote of forwarded requests
+pub mod forward;
 /// implementation for calling normal http endpoints
 pub mod http;
 /// general request urility
 pub mod util;
+/// proxies for the connections to the remote
+pub mod proxy;
+/// certificates for the connections to the remote
+pub mod tls;
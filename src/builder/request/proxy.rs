@@ -0,0 +1,184 @@
+use base64::Engine;
+use reqwest::Url;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::configuration::UpstreamSettings;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Returns the proxy for the url. Nothing is returned when the url is connected directly.
+///
+/// Without a configured proxy `HTTPS_PROXY`, `HTTP_PROXY`, `ALL_PROXY` and `NO_PROXY` are used.
+pub fn get_proxy(settings: &UpstreamSettings, url: &Url) -> Option<Url> {
+    let is_secure = matches!(url.scheme(), "https" | "wss");
+    let proxy = settings.proxy.clone().or_else(|| {
+        if is_secure {
+            get_env("HTTPS_PROXY")
+        } else {
+            get_env("HTTP_PROXY")
+        }
+        .or_else(|| get_env("ALL_PROXY"))
+    })?;
+    let no_proxy = settings.no_proxy.clone().or_else(|| get_env("NO_PROXY"));
+
+    if let (Some(no_proxy), Some(host)) = (no_proxy, url.host_str()) {
+        if is_no_proxy(&no_proxy, host) {
+            return None;
+        }
+    }
+
+    match Url::parse(&proxy) {
+        Ok(proxy) => Some(proxy),
+        Err(e) => {
+            tracing::error!("Invalid upstream proxy {}: {}", proxy, e);
+            None
+        }
+    }
+}
+
+/// Opens a connection to the host of the url. The connection goes through the proxy if there is
+/// one.
+pub async fn connect(settings: &UpstreamSettings, url: &str) -> Result<TcpStream, Error> {
+    let url = Url::parse(url)?;
+    let host = url.host_str().ok_or("url without host")?.to_owned();
+    let port = url.port_or_known_default().ok_or("url without port")?;
+
+    let connect = async {
+        let Some(proxy) = get_proxy(settings, &url) else {
+            return Ok(TcpStream::connect((host.as_str(), port)).await?);
+        };
+        tracing::trace!("Connect to {}:{} with proxy {}", host, port, proxy);
+        let proxy_host = proxy.host_str().ok_or("proxy without host")?;
+        let proxy_port = proxy.port_or_known_default().unwrap_or(1080);
+
+        match proxy.scheme() {
+            "http" => {
+                let stream = TcpStream::connect((proxy_host, proxy_port)).await?;
+                http_connect(stream, &proxy, &host, port).await
+            }
+            "socks5" | "socks5h" => {
+                let target = (host.as_str(), port);
+                let stream = if proxy.username().is_empty() {
+                    tokio_socks::tcp::Socks5Stream::connect((proxy_host, proxy_port), target)
+                        .await?
+                } else {
+                    tokio_socks::tcp::Socks5Stream::connect_with_password(
+                        (proxy_host, proxy_port),
+                        target,
+                        proxy.username(),
+                        proxy.password().unwrap_or_default(),
+                    )
+                    .await?
+                };
+                Ok(stream.into_inner())
+            }
+            scheme => Err(format!("Unsupported proxy scheme: {scheme}").into()),
+        }
+    };
+
+    tokio::time::timeout(settings.get_connect_timeout(), connect)
+        .await
+        .map_err(|_| "connect timeout")?
+}
+
+/// Opens a tunnel with `CONNECT`.
+async fn http_connect(
+    mut stream: TcpStream,
+    proxy: &Url,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, Error> {
+    let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            proxy.username(),
+            proxy.password().unwrap_or_default()
+        );
+        let credentials = base64::engine::general_purpose::STANDARD.encode(credentials);
+        request += &format!("Proxy-Authorization: Basic {credentials}\r\n");
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status).await?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("Proxy refused the tunnel: {}", status.trim()).into());
+    }
+
+    Ok(reader.into_inner())
+}
+
+fn get_env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .or_else(|_| std::env::var(name.to_lowercase()))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+/// Checks the host against a `NO_PROXY` list. Entries match the host and its subdomains.
+fn is_no_proxy(no_proxy: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches("*.").trim_start_matches('.'))
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            entry == "*"
+                || host.eq_ignore_ascii_case(entry)
+                || host
+                    .to_lowercase()
+                    .ends_with(&format!(".{}", entry.to_lowercase()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Url;
+
+    use crate::configuration::UpstreamSettings;
+
+    use super::{get_proxy, is_no_proxy};
+
+    #[test]
+    fn is_no_proxy_should_match_subdomains() {
+        let no_proxy = "localhost, .internal.example.com,10.0.0.1";
+
+        assert!(is_no_proxy(no_proxy, "localhost"));
+        assert!(is_no_proxy(no_proxy, "api.internal.example.com"));
+        assert!(is_no_proxy(no_proxy, "internal.example.com"));
+        assert!(is_no_proxy(no_proxy, "10.0.0.1"));
+        assert!(!is_no_proxy(no_proxy, "example.com"));
+        assert!(is_no_proxy("*", "example.com"));
+    }
+
+    #[test]
+    fn get_proxy_should_use_configuration() {
+        let settings = UpstreamSettings {
+            proxy: Some("socks5://127.0.0.1:1080".to_string()),
+            no_proxy: Some("localhost".to_string()),
+            ..UpstreamSettings::default()
+        };
+
+        assert_eq!(
+            get_proxy(&settings, &Url::parse("https://example.com/api").unwrap()),
+            Some(Url::parse("socks5://127.0.0.1:1080").unwrap())
+        );
+        assert_eq!(
+            get_proxy(&settings, &Url::parse("http://localhost:3000").unwrap()),
+            None
+        );
+    }
+}
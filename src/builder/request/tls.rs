@@ -0,0 +1,72 @@
+use native_tls::{Certificate, Identity, TlsConnector};
+
+use crate::configuration::{ClientCertificate, UpstreamSettings};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+const BEGIN_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Creates the TLS settings that are used for HTTP and WebSocket connections to the remote.
+pub fn get_tls_connector(
+    no_ssl_check: bool,
+    settings: &UpstreamSettings,
+) -> Result<TlsConnector, Error> {
+    let mut builder = TlsConnector::builder();
+    builder
+        .danger_accept_invalid_certs(no_ssl_check)
+        .danger_accept_invalid_hostnames(no_ssl_check);
+
+    for location in settings.ca_certificates.iter().flatten() {
+        let pem = std::fs::read_to_string(location)
+            .map_err(|e| format!("Unable to read {location}: {e}"))?;
+        for certificate in split_certificates(&pem) {
+            builder.add_root_certificate(Certificate::from_pem(certificate.as_bytes())?);
+        }
+    }
+
+    if let Some(client_certificate) = &settings.client_certificate {
+        builder.identity(get_identity(client_certificate)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn get_identity(client_certificate: &ClientCertificate) -> Result<Identity, Error> {
+    let read = |location: &str| {
+        std::fs::read(location).map_err(|e| format!("Unable to read {location}: {e}"))
+    };
+
+    let identity = match client_certificate {
+        ClientCertificate::Pem { certificate, key } => {
+            Identity::from_pkcs8(&read(certificate)?, &read(key)?)?
+        }
+        ClientCertificate::Pkcs12 { pkcs12, password } => {
+            Identity::from_pkcs12(&read(pkcs12)?, password)?
+        }
+    };
+
+    Ok(identity)
+}
+
+/// A bundle can contain multiple certificates, but they are added one by one.
+fn split_certificates(pem: &str) -> Vec<String> {
+    pem.split(BEGIN_CERTIFICATE)
+        .skip(1)
+        .map(|certificate| BEGIN_CERTIFICATE.to_owned() + certificate)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_certificates;
+
+    #[test]
+    fn split_certificates_should_return_all_certificates() {
+        let pem = "# bundle\n-----BEGIN CERTIFICATE-----\nA\n-----END CERTIFICATE-----\n-----BEGIN CERTIFICATE-----\nB\n-----END CERTIFICATE-----\n";
+
+        let certificates = split_certificates(pem);
+
+        assert_eq!(certificates.len(), 2);
+        assert!(certificates[1].starts_with("-----BEGIN CERTIFICATE-----\nB"));
+    }
+}
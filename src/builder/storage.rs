@@ -7,16 +7,56 @@ use tokio::{
     sync::Mutex,
 };
 
-use crate::configuration::{self, Configuration, Route, RouteMethod, WsMessage, WsMessageType};
+use crate::configuration::{
+    self, Configuration, RecordedRequest, Route, RouteMethod, WsMessage, WsMessageType,
+};
+
+use super::{core::RequestData, persist, ws::WsClientMessage};
+
+/// The folder that recordings are saved in
+pub const DB_FOLDER: &str = "./db";
+
+/// Where a response is recorded
+#[derive(Debug, Clone)]
+pub struct Destination {
+    /// The requested uri
+    pub uri: String,
+    /// The route that is updated instead of a route for the uri
+    pub route: Option<RefreshedRoute>,
+    /// The folder that new recordings are saved in
+    pub folder: String,
+}
 
-use super::ws::WsClientMessage;
+impl Destination {
+    /// Records the response as route for the uri.
+    pub fn new(uri: &str) -> Self {
+        Self {
+            uri: uri.to_owned(),
+            route: None,
+            folder: DB_FOLDER.to_owned(),
+        }
+    }
+}
 
-/// Modifies the configuration and filesystem to add more entryes
+/// An existing route that is updated by a refresh
+#[derive(Debug, Clone)]
+pub struct RefreshedRoute {
+    /// Path of the route that matched the request. It can contain `$$$`.
+    pub path: String,
+    /// The part of the uri that matched `$$$`
+    pub parameter: Option<String>,
+}
+
+/// Modifies the configuration and filesystem to add more entryes. The body is moved from the
+/// temporary file at `temp_location` to its final location. An existing route for the same path
+/// is updated instead. For a refresh this is the route that matched the request. The body of the
+/// request is saved next to the response. The configuration is saved by the background writer.
 pub async fn save(
     method: &RouteMethod,
-    uri: &str,
+    destination: &Destination,
     metadata: Option<configuration::Metadata>,
-    body: Vec<u8>,
+    request: RequestData,
+    temp_location: &str,
     config: Arc<Mutex<Configuration>>,
 ) -> Result<(), std::io::Error> {
     let content_type: Option<String> = metadata
@@ -26,40 +66,189 @@ pub async fn save(
         .get("content-type")
         .cloned()
         .map(|v| v.to_str().unwrap_or_default().to_string());
-    let path = get_save_path(uri, content_type.as_deref());
-    let mut config = config.lock().await;
-    if config.get_route(&path, method).is_none() {
-        let route = Route {
+    let uri = destination.uri.as_str();
+    let path = get_save_path(&destination.folder, uri, content_type.as_deref());
+    let (route_path, parameter) = match &destination.route {
+        Some(route) => (route.path.as_str(), route.parameter.as_deref()),
+        None => (uri, None),
+    };
+    // The configuration is only locked to find and update the route, not while files are written
+    let existing = config
+        .lock()
+        .await
+        .get_route_by_path_mut(route_path, method)
+        .map(|route| route.resource.clone());
+
+    if let Some(resource) = existing {
+        // Recordings of existing routes are replaced in place
+        let resource = resource.unwrap_or(path);
+        let path = match parameter {
+            Some(parameter) => resource.replace("$$$", parameter),
+            None => resource.clone(),
+        };
+        tracing::info!("Update route: {} {}", uri, path);
+
+        fs::create_dir_all(get_folders(&path)).await?;
+        // The request of one parameter does not describe the whole route
+        let request = match parameter {
+            Some(_) => None,
+            None => Some(save_request(request, &path, &destination.folder).await?),
+        };
+        persist(temp_location, &path).await?;
+
+        let mut config = config.lock().await;
+        let Some(route) = config.get_route_by_path_mut(route_path, method) else {
+            tracing::info!("Route was removed while it was recorded: {}", route_path);
+            return Ok(());
+        };
+        route.metadata = metadata;
+        route.resource = Some(resource);
+        route.body = None;
+        if request.is_some() {
+            route.request = request;
+        }
+        persist::mark_changed();
+    } else {
+        let mut route = Route {
             method: method.clone(),
             metadata,
             resource: Some(path.clone()),
+            body: None,
             path: uri.to_owned(),
             messages: vec![],
+            faults: vec![],
+            request: None,
+            throttle: None,
         };
         tracing::info!("Save route: {:?}", route);
 
-        config.routes.push(route);
-
         let folders = get_folders(&path);
-
-        match check_existing_file(folders.as_str()).await {
-            Ok(resource_changes) => {
-                for (from, to) in resource_changes {
-                    if let Some(route) = config.get_route_by_resource_mut(&from.to_owned(), method)
-                    {
-                        route.resource = Some(to.to_string());
-                    }
-                }
+        let resource_changes = check_existing_file(folders.as_str()).await?;
+        fs::create_dir_all(&folders).await?;
+        route.request = Some(save_request(request, &path, &destination.folder).await?);
+        persist(temp_location, &path).await?;
+
+        let mut config = config.lock().await;
+        for (from, to) in resource_changes {
+            if let Some(route) = config.get_route_by_resource_mut(&from, method) {
+                route.resource = Some(to);
             }
-            Err(e) => return Err(e),
         }
-        save_file(path.as_str(), body, folders.as_str()).await?;
-        configuration::save_configuration(config.to_owned()).await?;
+        // Another recording of the same route may have been saved in the meantime
+        match config.get_route_by_path_mut(uri, method) {
+            Some(existing) => *existing = route,
+            None => config.routes.push(route),
+        }
+        persist::mark_changed();
     }
 
     Ok(())
 }
 
+/// Saves the body of the request in a file next to the resource. The file is only created when
+/// there is a body.
+async fn save_request(
+    request: RequestData,
+    resource: &str,
+    folder: &str,
+) -> Result<RecordedRequest, std::io::Error> {
+    let mut recorded = request.request;
+    if request.body.is_empty() {
+        return Ok(recorded);
+    }
+
+    let location = resource.to_owned() + ".request";
+    let (temp_location, mut file) = create_temp_file(folder).await?;
+    file.write_all(&request.body).await?;
+    sync_file(&mut file).await?;
+    drop(file);
+    persist(&temp_location, &location).await?;
+    recorded.body = Some(location);
+
+    Ok(recorded)
+}
+
+/// Creates a file that a response can be written to while it is received. It is created in the
+/// folder of the recordings, so that it can be renamed to its location.
+pub async fn create_temp_file(folder: &str) -> Result<(String, File), std::io::Error> {
+    let temp_folder = get_temp_folder(folder);
+    fs::create_dir_all(&temp_folder).await?;
+    let location = format!("{}/{:016x}", temp_folder, rand::random::<u64>());
+    let file = File::create(&location).await?;
+
+    Ok((location, file))
+}
+
+fn get_temp_folder(folder: &str) -> String {
+    format!("{folder}/.tmp")
+}
+
+/// Removes the temporary files of recordings that did not finish, e.g. because moxy was killed.
+/// It has to be called before recordings are started.
+pub async fn clear_temp_files(folder: &str) {
+    let temp_folder = get_temp_folder(folder);
+    match fs::remove_dir_all(&temp_folder).await {
+        Ok(()) => tracing::info!("Removed unfinished recordings in {}", temp_folder),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => tracing::error!("Unable to remove {}: {}", temp_folder, e),
+    }
+}
+
+/// Writes the buffered data of a file to disk.
+pub async fn sync_file(file: &mut File) -> Result<(), std::io::Error> {
+    file.flush().await?;
+    file.sync_all().await
+}
+
+/// Moves a temporary file to its location. The file has to be synced to disk before, so that a
+/// crash leaves either the old or the new file.
+pub async fn persist(temp_location: &str, location: &str) -> Result<(), std::io::Error> {
+    fs::rename(temp_location, location).await?;
+    sync_folder(location).await
+}
+
+/// Writes a file without ever leaving a partially written file behind. The content is written to
+/// a temporary file next to it, synced to disk and renamed over the file.
+pub async fn write_atomic(location: &str, content: &[u8]) -> Result<(), std::io::Error> {
+    let temp_location = format!("{}.{:016x}.tmp", location, rand::random::<u64>());
+    let result = async {
+        let mut file = File::create(&temp_location).await?;
+        file.write_all(content).await?;
+        sync_file(&mut file).await?;
+        drop(file);
+        persist(&temp_location, location).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_location).await;
+    }
+
+    result
+}
+
+/// Syncs the folder of a file, so that a rename survives a crash.
+#[cfg(unix)]
+async fn sync_folder(location: &str) -> Result<(), std::io::Error> {
+    let folder = Path::new(location)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+
+    File::open(folder).await?.sync_all().await
+}
+
+#[cfg(not(unix))]
+async fn sync_folder(_location: &str) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// Removes a temporary file that will not be saved.
+pub async fn remove_temp_file(location: &str) {
+    if let Err(e) = fs::remove_file(location).await {
+        tracing::error!("Unable to remove temporary file {}: {}", location, e);
+    }
+}
+
 /// This function will check if there is a file in the current folder structure.
 /// Previous: Triggered with a call to /api/some-service/results
 /// folders:
@@ -102,10 +291,8 @@ async fn folder_check(folder: &str) -> Result<Option<String>, std::io::Error> {
         fs::remove_file(&folder).await?;
         fs::create_dir_all(&folder).await?;
         let path = folder.to_owned() + "/index";
-        let mut index_file = File::create(&path).await?;
-        if let Some(Ok(prefious_file)) = prefious_file {
-            index_file.write_all(&prefious_file).await?;
-        }
+        let prefious_file = prefious_file.and_then(Result::ok).unwrap_or_default();
+        write_atomic(&path, &prefious_file).await?;
 
         return Ok(Some(path));
     }
@@ -148,7 +335,7 @@ pub async fn save_ws_client_message(path: &str, messages: Vec<WsClientMessage>)
                 path += ".json";
             }
 
-            let path = get_save_path(path.as_str(), None);
+            let path = get_save_path(DB_FOLDER, path.as_str(), None);
 
             if message.offset <= 5 {
                 (
@@ -197,7 +384,7 @@ pub async fn save_ws_client_message(path: &str, messages: Vec<WsClientMessage>)
     .await
     .unwrap();
 
-    return messages.iter().map(|(msg, _)| msg.clone()).collect();
+    messages.iter().map(|(msg, _)| msg.clone()).collect()
 }
 
 fn is_json(message: &[u8]) -> bool {
@@ -214,16 +401,14 @@ fn is_json(message: &[u8]) -> bool {
 /// Saves a file to the expected location
 async fn save_file(location: &str, body: Vec<u8>, folder: &str) -> Result<(), std::io::Error> {
     fs::create_dir_all(&folder).await?;
-    let mut file = File::create(location).await?;
-    file.write_all(&body).await?;
 
-    Ok(())
+    write_atomic(location, &body).await
 }
 
 const FALLBACK_CHAR: &str = "_";
 
-/// Will generate a file location based on a uri.
-pub fn get_save_path(uri: &str, content_type: Option<&str>) -> String {
+/// Will generate a file location in the folder based on a uri.
+pub fn get_save_path(folder: &str, uri: &str, content_type: Option<&str>) -> String {
     let uri = uri.replace(['*', '?', '"', '<', '>', ':', '|'], FALLBACK_CHAR);
 
     let file_suffix = if uri.ends_with(".txt") || uri.ends_with(".json") {
@@ -231,7 +416,7 @@ pub fn get_save_path(uri: &str, content_type: Option<&str>) -> String {
     } else {
         get_extension(content_type)
     };
-    let mut path = "./db".to_owned() + &uri.to_string();
+    let mut path = folder.to_owned() + &uri.to_string();
 
     if path.ends_with('/') {
         path += "index";
@@ -296,7 +481,28 @@ fn get_folders(path: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::builder::storage::{get_folders_to_check, get_save_path, is_json};
+    use crate::builder::storage::{
+        clear_temp_files, create_temp_file, get_folders_to_check, get_save_path, is_json, DB_FOLDER,
+    };
+
+    #[tokio::test]
+    async fn clear_temp_files_should_remove_unfinished_recordings() {
+        let folder = std::env::temp_dir().join(format!("moxy-{:016x}", rand::random::<u64>()));
+        let folder = folder.to_string_lossy().into_owned();
+        tokio::fs::create_dir_all(&folder).await.unwrap();
+        tokio::fs::write(format!("{folder}/recorded.txt"), "recorded")
+            .await
+            .unwrap();
+        let (temp_location, file) = create_temp_file(&folder).await.unwrap();
+        drop(file);
+
+        clear_temp_files(&folder).await;
+        clear_temp_files(&folder).await;
+
+        assert!(!std::path::Path::new(&temp_location).exists());
+        assert!(std::path::Path::new(&format!("{folder}/recorded.txt")).exists());
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+    }
 
     #[test]
     fn get_folders_to_check_should_return_correct_result_1() {
@@ -328,7 +534,7 @@ mod tests {
         let input = "/api/some-service/micmine";
         let expected = "./db/api/some-service/micmine.txt";
 
-        assert_eq!(get_save_path(input, None), expected);
+        assert_eq!(get_save_path(DB_FOLDER, input, None), expected);
     }
 
     #[test]
@@ -336,19 +542,19 @@ mod tests {
         let input = "/api/some-service/micmine/";
         let expected = "./db/api/some-service/micmine/index.txt";
 
-        assert_eq!(get_save_path(input, None), expected);
+        assert_eq!(get_save_path(DB_FOLDER, input, None), expected);
     }
 
     #[test]
     fn get_save_path_should_start_with_db() {
-        let path = get_save_path("/index.html", None);
+        let path = get_save_path(DB_FOLDER, "/index.html", None);
 
         assert!(&path.starts_with("./db"));
     }
 
     #[test]
     fn get_save_path_should_add_index_if_folder() {
-        let path = get_save_path("/", None);
+        let path = get_save_path(DB_FOLDER, "/", None);
 
         assert!(&path.ends_with("/index.txt"));
     }
@@ -13,15 +13,15 @@ use hyper::{http::HeaderName, upgrade::Upgraded, Request};
 use hyper_tungstenite::{tungstenite::Message, WebSocketStream};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{
-    connect_async_tls_with_config, tungstenite::protocol::WebSocketConfig, MaybeTlsStream,
+    client_async_tls_with_config, tungstenite::protocol::WebSocketConfig, MaybeTlsStream,
 };
 
 use crate::{
     builder::request,
-    configuration::{Metadata, Route, RouteMethod, WsMessagType},
+    configuration::{Metadata, Redaction, Route, RouteMethod, UpstreamSettings, WsMessagType},
 };
 
-use super::storage;
+use super::{redact, storage};
 
 /// A Message that is sent or received on a websocket
 #[derive(Debug, Clone)]
@@ -73,13 +73,16 @@ impl WsClientMessage {
 }
 
 
-/// generate route for a websocket
+/// generate route for a websocket. The recorded headers and messages are redacted, the remote
+/// gets the original ones.
 pub async fn build_ws(
     uri: &str,
     metadata: Option<Metadata>,
     remote: impl Into<String> + std::marker::Send + 'static,
     websocket: hyper_tungstenite::HyperWebsocket,
     no_ssl_check: bool,
+    settings: UpstreamSettings,
+    redaction: Redaction,
 ) -> Result<Route, u8> {
     let path = uri;
     let mut route = Route {
@@ -87,8 +90,15 @@ pub async fn build_ws(
         metadata: metadata.clone(),
         path: path.to_string(),
         resource: None,
+        body: None,
         messages: vec![],
+        faults: vec![],
+        request: None,
+        throttle: None,
     };
+    if let Some(metadata) = route.metadata.as_mut() {
+        redact::redact_headers(&redaction, &mut metadata.header);
+    }
 
     let empty_remote_messages: Vec<WsClientMessage> = vec![];
     let remote_messages = Arc::new(Mutex::new(empty_remote_messages));
@@ -125,18 +135,9 @@ pub async fn build_ws(
         tracing::trace!("connect to remote");
         let url = get_ws_url(&request::util::get_url_str(&uri, remote.into()));
         tracing::trace!("{:?}", url);
-        let request = get_request(url, metadata);
-
-        match connect_async_tls_with_config(
-            request,
-            Some(WebSocketConfig::default()),
-            true,
-            get_tls_connector(no_ssl_check),
-        )
-        .await
-        {
+        match connect_remote(url, metadata, no_ssl_check, &settings).await {
             //match connect_async(url).await {
-            Ok((socket, _response)) => {
+            Ok(socket) => {
                 let (write, read) = socket.split();
 
                 tokio::select! {
@@ -160,13 +161,10 @@ pub async fn build_ws(
         let start = Instant::now();
         let remote_messages2 = remote_messages2.clone();
         loop {
-            match rx_r2.recv().await {
-                Ok(message) => {
-                    let offset = start.elapsed().as_secs();
-                    let mut messages = remote_messages2.lock().await;
-                    messages.push(WsClientMessage::from(message.clone(), offset));
-                }
-                Err(_) => (),
+            if let Ok(message) = rx_r2.recv().await {
+                let offset = start.elapsed().as_secs();
+                let mut messages = remote_messages2.lock().await;
+                messages.push(WsClientMessage::from(message.clone(), offset));
             }
         }
     }));
@@ -189,24 +187,57 @@ pub async fn build_ws(
     //}
     //}
 
-    let messages = remote_messages.lock().await.to_owned();
+    let mut messages = remote_messages.lock().await.to_owned();
+    for message in messages.iter_mut() {
+        if let Some(content) = redact::redact_body(&redaction, &message.content) {
+            message.content = content;
+        }
+    }
     route.messages = storage::save_ws_client_message(path, messages).await;
 
     Ok(route)
 }
 
+/// Forward a websocket to the remote without recording it
+pub async fn proxy_ws(
+    uri: &str,
+    metadata: Option<Metadata>,
+    remote: impl Into<String>,
+    websocket: hyper_tungstenite::HyperWebsocket,
+    no_ssl_check: bool,
+    settings: UpstreamSettings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let websocket = websocket.await?;
+    let url = get_ws_url(&request::util::get_url_str(uri, remote.into()));
+    let socket = connect_remote(url, metadata, no_ssl_check, &settings).await?;
+
+    let (client_write, client_read) = websocket.split();
+    let (remote_write, remote_read) = socket.split();
+    // Contains all user messages
+    let (tx_u, rx_u) = tokio::sync::mpsc::channel(32);
+    // Contains all remote messages
+    let (tx_r, rx_r) = tokio::sync::broadcast::channel(32);
+
+    tokio::select! {
+        _ = read_ws_client(client_read, tx_u) => {},
+        _ = send_ws_client(client_write, rx_r) => {},
+        _ = read_ws_remote(remote_read, tx_r) => {},
+        _ = send_ws_remote(remote_write, rx_u) => {},
+    }
+    tracing::trace!("[WS] Passthrough done");
+
+    Ok(())
+}
+
 /// Send rx to remote
 pub async fn send_ws_remote(
     mut write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
     mut rx: tokio::sync::mpsc::Receiver<Message>,
 ) {
-    loop {
-        match rx.recv().await {
-            Some(message) => match write.send(message).await {
-                Ok(_data) => tracing::trace!("[WS] sent message to server"),
-                Err(_) => tracing::trace!("[WS] Unable to send data to server"),
-            },
-            None => (),
+    while let Some(message) = rx.recv().await {
+        match write.send(message).await {
+            Ok(_data) => tracing::trace!("[WS] sent message to server"),
+            Err(_) => tracing::trace!("[WS] Unable to send data to server"),
         }
     }
 }
@@ -228,7 +259,7 @@ pub async fn read_ws_remote(
             Some(Err(err)) => {
                 tracing::error!("Got error while reading remote websocket: {err:?}");
             }
-            None => (),
+            None => return,
         }
     }
 }
@@ -250,7 +281,7 @@ pub async fn read_ws_client(
             Some(Err(err)) => {
                 tracing::error!("Got error while reading client websocket: {err:?}");
             }
-            None => (),
+            None => return,
         }
     }
 }
@@ -269,6 +300,7 @@ pub async fn send_ws_client(
                     tracing::trace!("[WS] Unable to send data to client");
                 }
             }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
             Err(err) => {
                 tracing::error!("Got error while messaging to websocket: {err}");
             }
@@ -286,30 +318,60 @@ pub fn get_ws_url(url: &str) -> String {
     url
 }
 
-fn get_tls_connector(no_ssl_check: bool) -> Option<tokio_tungstenite::Connector> {
-    let Ok(connector) = native_tls::TlsConnector::builder()
-        .danger_accept_invalid_certs(no_ssl_check)
-        .danger_accept_invalid_hostnames(no_ssl_check)
-        .build() else {
-        tracing::error!("Unable to create tls connector");
-        return None;
-    };
+/// Connects to the websocket of the remote. The connection goes through the upstream proxy if
+/// there is one.
+async fn connect_remote(
+    url: String,
+    metadata: Option<Metadata>,
+    no_ssl_check: bool,
+    settings: &UpstreamSettings,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn std::error::Error + Send + Sync>>
+{
+    let stream = request::proxy::connect(settings, &url).await?;
+    stream.set_nodelay(true)?;
+    let request = get_request(url, metadata);
+    let (socket, _response) = client_async_tls_with_config(
+        request,
+        stream,
+        Some(WebSocketConfig::default()),
+        Some(get_tls_connector(no_ssl_check, settings)?),
+    )
+    .await?;
+
+    Ok(socket)
+}
 
-    Some(tokio_tungstenite::Connector::NativeTls(connector))
+/// Creates the TLS settings of the connection. Fails instead of falling back to the default
+/// settings, which would ignore the configured certificates.
+fn get_tls_connector(
+    no_ssl_check: bool,
+    settings: &UpstreamSettings,
+) -> Result<tokio_tungstenite::Connector, Box<dyn std::error::Error + Send + Sync>> {
+    let connector = request::tls::get_tls_connector(no_ssl_check, settings)
+        .map_err(|e| format!("Unable to create tls connector: {e}"))?;
+
+    Ok(tokio_tungstenite::Connector::NativeTls(connector))
 }
 
-/// Creates a reuest with the specifed url and header
+/// Creates a reuest with the specifed url and header. The host is the host of the remote.
 fn get_request(url: String, metadata: Option<Metadata>) -> Request<()> {
+    let host = url
+        .parse::<hyper::Uri>()
+        .ok()
+        .and_then(|u| u.authority().map(|a| a.as_str().to_owned()));
     let mut request = Request::builder().method("GET").uri(url);
 
-    if let Some(metadata) = metadata {
-        if let Some(request_header) = request.headers_mut() {
+    if let Some(request_header) = request.headers_mut() {
+        if let Some(metadata) = metadata {
             for (key, value) in metadata.header {
                 if let Some(key) = key {
                     request_header.insert::<HeaderName>(key, value);
                 }
             }
         }
+        if let Some(host) = host.and_then(|h| h.parse().ok()) {
+            request_header.insert(hyper::header::HOST, host);
+        }
     }
 
     request.body(()).unwrap()
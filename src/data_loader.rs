@@ -1,30 +1,61 @@
 //! Load routes from filesystem
+use std::time::SystemTime;
+
+use hyper::Body;
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
 use crate::configuration::Route;
 
+/// A file that is streamed to the client instead of loading it into memory.
+pub struct Resource {
+    /// File storage location
+    pub location: String,
+    /// Opened file
+    pub file: File,
+    /// File size in bytes
+    pub length: u64,
+    /// Last modification of the file
+    pub modified: Option<SystemTime>,
+}
+
+impl Resource {
+    /// Stream the file content
+    pub fn into_body(self) -> Body {
+        Body::wrap_stream(ReaderStream::new(self.file))
+    }
+}
+
 /// Call file with replaced parameter when there is a parameter.
-pub async fn load(route: &Route, parameter: Option<&str>) -> Option<Vec<u8>> {
+pub async fn load(route: &Route, parameter: Option<&str>) -> Option<Resource> {
     if let Some(resource) = &route.resource {
-        return match if let Some(parameter) = parameter {
+        return if let Some(parameter) = parameter {
             let dynamic_resource = resource.replace("$$$", parameter);
             file(dynamic_resource.as_str()).await
         } else {
             file(resource).await
-        } {
-            Ok(data) => Some(data.to_vec()),
-            Err(_) => None,
-        };
+        }
+        .ok();
     }
 
     None
 }
 
 /// Load file for route.
-pub async fn file(resource: &str) -> Result<Vec<u8>, std::io::Error> {
+pub async fn file(resource: &str) -> Result<Resource, std::io::Error> {
     tracing::trace!("Load File: {}", resource);
-    match tokio::fs::read(&resource).await {
-        Ok(data) => Ok(data),
-        Err(e) => Err(e),
+    let file = File::open(&resource).await?;
+    let metadata = file.metadata().await?;
+    if metadata.is_dir() {
+        return Err(std::io::Error::other("Resource is a directory"));
     }
+
+    Ok(Resource {
+        location: resource.to_owned(),
+        file,
+        length: metadata.len(),
+        modified: metadata.modified().ok(),
+    })
 }
 
 /// Load file for route.
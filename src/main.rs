@@ -5,13 +5,35 @@ pub mod configuration;
 #[warn(missing_docs)]
 pub mod data_loader;
 #[warn(missing_docs)]
+pub mod response;
+#[warn(missing_docs)]
 pub mod router;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt::init();
 
-    router::start().await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--recover") {
+        configuration::enable_recover_mode();
+    }
+    let config = match configuration::get_configuration().await {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", e);
+            tracing::error!(
+                "Fix the configuration or start with --recover to use the backup or the defaults. The original file is never changed in recover mode."
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if args.first().map(String::as_str) == Some("scrub") {
+        builder::redact::scrub(config).await?;
+        return Ok(());
+    }
+
+    router::start(config).await;
 
     Ok(())
 }
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use hyper::{
+    header::{self, HeaderValue},
+    http::response::Builder,
+    Body, HeaderMap, Method, Response, StatusCode,
+};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::data_loader::Resource;
+
+/// Headers that are kept in a `304 Not Modified` response
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 6] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::ETAG,
+    header::EXPIRES,
+    header::LAST_MODIFIED,
+    header::VARY,
+];
+
+/// Generated etags by file location. They are only reused while the size and modification time
+/// of the file stay the same.
+type EtagCache = HashMap<String, (u64, Option<SystemTime>, String)>;
+
+fn etag_cache() -> &'static Mutex<EtagCache> {
+    static CACHE: OnceLock<Mutex<EtagCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Adds `etag` and `last-modified` to the response when they were not recorded.
+pub async fn add_validators(mut response: Builder, resource: &mut Resource) -> Builder {
+    let Some(headers) = response.headers_mut() else {
+        return response;
+    };
+
+    if !headers.contains_key(header::ETAG) {
+        match get_etag(resource).await {
+            Ok(etag) => {
+                headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+            }
+            Err(e) => tracing::error!("Unable to create etag for {}: {}", resource.location, e),
+        }
+    }
+
+    if !headers.contains_key(header::LAST_MODIFIED) {
+        if let Some(modified) = resource.modified {
+            headers.insert(
+                header::LAST_MODIFIED,
+                HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap(),
+            );
+        }
+    }
+
+    response
+}
+
+/// Returns `304 Not Modified` when the client already has the current version of the resource.
+pub fn not_modified(
+    method: &Method,
+    request_headers: &HeaderMap,
+    response: &Builder,
+) -> Option<Response<Body>> {
+    if method != Method::GET && method != Method::HEAD {
+        return None;
+    }
+    let headers = response.headers_ref()?;
+
+    let is_not_modified = if let Some(if_none_match) =
+        get_header(request_headers, header::IF_NONE_MATCH)
+    {
+        get_header(headers, header::ETAG)
+            .map(|etag| etag_matches(if_none_match, etag))
+            .unwrap_or(false)
+    } else if let Some(if_modified_since) = get_header(request_headers, header::IF_MODIFIED_SINCE) {
+        let last_modified = get_header(headers, header::LAST_MODIFIED)
+            .and_then(|l| httpdate::parse_http_date(l).ok());
+        let if_modified_since = httpdate::parse_http_date(if_modified_since).ok();
+
+        matches!((last_modified, if_modified_since), (Some(l), Some(i)) if l <= i)
+    } else {
+        false
+    };
+
+    if !is_not_modified {
+        return None;
+    }
+
+    let mut not_modified = Response::builder().status(StatusCode::NOT_MODIFIED);
+    for key in NOT_MODIFIED_HEADERS {
+        if let Some(value) = headers.get(&key) {
+            not_modified = not_modified.header(key, value);
+        }
+    }
+
+    Some(not_modified.body(Body::empty()).unwrap())
+}
+
+fn get_header(headers: &HeaderMap, key: header::HeaderName) -> Option<&str> {
+    headers.get(key).and_then(|v| v.to_str().ok())
+}
+
+/// Weak comparison of the `if-none-match` list with the etag
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+
+    if_none_match
+        .split(',')
+        .map(|e| e.trim())
+        .any(|e| e == "*" || e.trim_start_matches("W/") == etag)
+}
+
+/// Creates a strong etag from the content of the resource.
+async fn get_etag(resource: &mut Resource) -> Result<String, std::io::Error> {
+    if let Some((length, modified, etag)) = etag_cache().lock().unwrap().get(&resource.location) {
+        if *length == resource.length && *modified == resource.modified {
+            return Ok(etag.clone());
+        }
+    }
+
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = resource.file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    resource.file.seek(SeekFrom::Start(0)).await?;
+
+    let etag = format!("\"{:x}\"", hasher.finalize());
+    etag_cache().lock().unwrap().insert(
+        resource.location.clone(),
+        (resource.length, resource.modified, etag.clone()),
+    );
+
+    Ok(etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header, http::response::Builder, HeaderMap, Method, Response, StatusCode};
+
+    use super::{etag_matches, not_modified};
+
+    fn response() -> Builder {
+        Response::builder()
+            .status(200)
+            .header(header::ETAG, "\"abc\"")
+            .header(header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+            .header(header::CONTENT_TYPE, "text/plain")
+    }
+
+    #[test]
+    fn etag_matches_should_use_weak_comparison() {
+        assert!(etag_matches("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"xyz\", \"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"xyz\"", "\"abc\""));
+    }
+
+    #[test]
+    fn not_modified_should_answer_matching_etag() {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::IF_NONE_MATCH, "\"abc\"".parse().unwrap());
+
+        let response = not_modified(&Method::GET, &request_headers, &response()).unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.headers().get(header::ETAG).is_some());
+        assert!(response.headers().get(header::CONTENT_TYPE).is_none());
+    }
+
+    #[test]
+    fn not_modified_should_compare_dates() {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(
+            header::IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        assert!(not_modified(&Method::GET, &request_headers, &response()).is_some());
+
+        request_headers.insert(
+            header::IF_MODIFIED_SINCE,
+            "Tue, 20 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        assert!(not_modified(&Method::GET, &request_headers, &response()).is_none());
+    }
+
+    #[test]
+    fn not_modified_should_ignore_date_when_etag_is_sent() {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::IF_NONE_MATCH, "\"xyz\"".parse().unwrap());
+        request_headers.insert(
+            header::IF_MODIFIED_SINCE,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+
+        assert!(not_modified(&Method::GET, &request_headers, &response()).is_none());
+    }
+}
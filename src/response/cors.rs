@@ -0,0 +1,206 @@
+use hyper::{
+    header::{self, HeaderValue},
+    Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+
+use crate::configuration::Cors;
+
+/// Checks if the request is a CORS preflight
+pub fn is_preflight(request: &Request<Body>) -> bool {
+    request.method() == Method::OPTIONS
+        && request.headers().contains_key(header::ORIGIN)
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Answers a preflight without calling the remote or loading a route.
+pub fn preflight(cors: &Cors, request_headers: &HeaderMap) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+    let origin = request_headers.get(header::ORIGIN);
+    add_headers(cors, origin, response.headers_mut());
+
+    if !response
+        .headers()
+        .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+    {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    let methods = if cors.allowed_methods.is_empty() {
+        request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .cloned()
+    } else {
+        join(&cors.allowed_methods)
+    };
+    if let Some(methods) = methods {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+    }
+
+    let allowed_headers = if cors.allowed_headers.is_empty() {
+        request_headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .cloned()
+    } else {
+        join(&cors.allowed_headers)
+    };
+    if let Some(allowed_headers) = allowed_headers {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+    }
+
+    if let Some(max_age) = cors.max_age {
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.into());
+    }
+
+    response
+}
+
+/// Replaces the CORS headers of a served or proxied response.
+pub fn add_headers(cors: &Cors, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+    for key in [
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        header::ACCESS_CONTROL_MAX_AGE,
+    ] {
+        headers.remove(key);
+    }
+
+    let Some(origin) = origin else {
+        return;
+    };
+    let Some(allowed_origin) = get_allowed_origin(cors, origin) else {
+        tracing::info!(
+            "Origin is not allowed by the cors configuration: {:?}",
+            origin
+        );
+        return;
+    };
+
+    if allowed_origin != "*" {
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+    }
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+
+    if cors.allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if let Some(exposed_headers) = join(&cors.exposed_headers) {
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed_headers);
+    }
+}
+
+/// A wildcard can not be used together with credentials. The origin is sent back instead.
+fn get_allowed_origin(cors: &Cors, origin: &HeaderValue) -> Option<HeaderValue> {
+    let is_wildcard = cors.allowed_origins.iter().any(|o| o == "*");
+    if is_wildcard && !cors.allow_credentials {
+        return Some(HeaderValue::from_static("*"));
+    }
+
+    let origin_str = origin.to_str().ok()?;
+    if is_wildcard || cors.allowed_origins.iter().any(|o| o == origin_str) {
+        return Some(origin.clone());
+    }
+
+    None
+}
+
+fn join(values: &[String]) -> Option<HeaderValue> {
+    if values.is_empty() {
+        return None;
+    }
+
+    HeaderValue::from_str(&values.join(", ")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header, HeaderMap};
+
+    use crate::configuration::Cors;
+
+    use super::{add_headers, preflight};
+
+    fn cors(allowed_origins: &[&str], allow_credentials: bool) -> Cors {
+        Cors {
+            allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+            allow_credentials,
+            max_age: Some(600),
+            ..Cors::default()
+        }
+    }
+
+    fn request_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, "http://localhost:3000".parse().unwrap());
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            "PUT".parse().unwrap(),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            "content-type".parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn preflight_should_allow_requested_method_and_headers() {
+        let response = preflight(&cors(&["http://localhost:3000"], false), &request_headers());
+        let headers = response.headers();
+
+        assert_eq!(response.status(), 204);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:3000"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+    }
+
+    #[test]
+    fn add_headers_should_echo_origin_for_wildcard_with_credentials() {
+        let mut headers = HeaderMap::new();
+        let origin = "http://localhost:3000".parse().unwrap();
+
+        add_headers(&cors(&["*"], true), Some(&origin), &mut headers);
+
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:3000"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+
+    #[test]
+    fn add_headers_should_replace_recorded_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            "https://example.com".parse().unwrap(),
+        );
+        let origin = "http://evil.example".parse().unwrap();
+
+        add_headers(
+            &cors(&["http://localhost:3000"], false),
+            Some(&origin),
+            &mut headers,
+        );
+
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+}
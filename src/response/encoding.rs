@@ -0,0 +1,167 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+use futures_util::TryStreamExt;
+use hyper::{
+    header::{self, HeaderValue},
+    Body, HeaderMap, Response, StatusCode,
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Content encodings that can be created by moxy
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Preferred order when the client accepts multiple encodings with the same quality
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Compresses the body with the best encoding the client accepts. Responses that are already
+/// encoded or that do not benefit from compression are not changed.
+pub fn compress(request_headers: &HeaderMap, mut response: Response<Body>) -> Response<Body> {
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+        || !response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .map(is_compressible)
+            .unwrap_or(false)
+    {
+        return response;
+    }
+
+    let Some(encoding) = request_headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|a| a.to_str().ok())
+        .and_then(get_encoding)
+    else {
+        return response;
+    };
+    tracing::trace!("Compress response with {}", encoding.name());
+
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    // The compressed body is a different representation and can not use the strong etag
+    if let Some(etag) = headers.get(header::ETAG).and_then(|e| e.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            let weak_etag = HeaderValue::from_str(&format!("W/{etag}")).unwrap();
+            headers.insert(header::ETAG, weak_etag);
+        }
+    }
+
+    let body = std::mem::replace(response.body_mut(), Body::empty());
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    *response.body_mut() = match encoding {
+        Encoding::Brotli => Body::wrap_stream(ReaderStream::new(BrotliEncoder::new(reader))),
+        Encoding::Gzip => Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader))),
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(ZlibEncoder::new(reader))),
+    };
+
+    response
+}
+
+/// Picks the encoding with the highest quality from `accept-encoding`.
+fn get_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let accepted: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let name = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            Some((name, quality))
+        })
+        .collect();
+
+    let get_quality = |encoding: &Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in Encoding::ALL {
+        let quality = get_quality(&encoding);
+        if quality > 0.0 && best.map(|(_, q)| quality > q).unwrap_or(true) {
+            best = Some((encoding, quality));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let content_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    content_type.starts_with("text/")
+        || content_type.ends_with("+json")
+        || content_type.ends_with("+xml")
+        || matches!(
+            content_type.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/x-javascript"
+                | "application/xml"
+                | "image/svg+xml"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_encoding, is_compressible, Encoding};
+
+    #[test]
+    fn get_encoding_should_prefer_brotli() {
+        assert_eq!(get_encoding("gzip, deflate, br"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn get_encoding_should_use_quality() {
+        assert_eq!(
+            get_encoding("br;q=0.5, gzip;q=0.9, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(get_encoding("br;q=0, gzip"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn get_encoding_should_support_wildcard_and_identity() {
+        assert_eq!(get_encoding("*"), Some(Encoding::Brotli));
+        assert_eq!(get_encoding("identity"), None);
+        assert_eq!(get_encoding("zstd"), None);
+    }
+
+    #[test]
+    fn is_compressible_should_detect_text() {
+        assert!(is_compressible("application/json; charset=utf-8"));
+        assert!(is_compressible("text/html"));
+        assert!(!is_compressible("image/png"));
+    }
+}
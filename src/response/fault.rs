@@ -0,0 +1,179 @@
+use std::sync::{Mutex, OnceLock};
+
+use futures_util::future;
+use hyper::{body::HttpBody, Body, Response};
+use rand::Rng;
+
+use crate::configuration::{self, Configuration, Fault, RouteMethod};
+
+/// Fault injection that was turned on or off at runtime. It is kept out of the configuration, so
+/// that it is never saved to `moxy.json`.
+fn switch() -> &'static Mutex<Option<bool>> {
+    static SWITCH: OnceLock<Mutex<Option<bool>>> = OnceLock::new();
+    SWITCH.get_or_init(|| Mutex::new(None))
+}
+
+/// Turns all fault rules on or off until moxy is stopped. This overrides `faults.enabled`.
+pub fn set_enabled(enabled: bool) {
+    *switch().lock().unwrap() = Some(enabled);
+}
+
+/// Returns the fault that should be applied to this request. Route faults are checked before
+/// the global ones.
+pub fn get_fault(config: &Configuration, uri: &str, method: &RouteMethod) -> Option<Fault> {
+    let settings = config.faults.clone().unwrap_or_default();
+    if !switch().lock().unwrap().unwrap_or(settings.enabled) {
+        return None;
+    }
+
+    let (route, _parameter) = configuration::get_route(&config.routes, uri, method);
+    let route_faults = route.map(|r| r.faults.as_slice()).unwrap_or_default();
+
+    let mut rng = rand::thread_rng();
+    route_faults
+        .iter()
+        .chain(settings.rules.iter())
+        .find(|fault| rng.gen_range(0..100) < fault.rate)
+        .cloned()
+}
+
+/// Returns a response with the configured status code of the fault
+pub fn status(fault: &Fault) -> Response<Body> {
+    Response::builder()
+        .status(fault.code.unwrap_or(500))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Waits for the configured time. Without a time it will never finish.
+pub async fn hang(fault: &Fault) {
+    if let Some(time) = fault.get_time() {
+        tokio::time::sleep(time).await;
+    } else {
+        future::pending::<()>().await;
+    }
+}
+
+/// Sends only the configured amount of bytes of the body and aborts the connection afterwards.
+/// Without an amount, half of the `content-length` is sent. The `content-length` still contains
+/// the full size so the client can detect it.
+pub fn truncate(response: Response<Body>, fault: &Fault) -> Response<Body> {
+    let (parts, mut body) = response.into_parts();
+    let size = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    let mut remaining = fault.bytes.or(size.map(|s| s / 2)).unwrap_or_default();
+
+    let (mut sender, truncated) = Body::channel();
+    tokio::spawn(async move {
+        while remaining > 0 {
+            let Some(Ok(mut data)) = body.data().await else {
+                break;
+            };
+            data.truncate(remaining);
+            remaining -= data.len();
+            if sender.send_data(data).await.is_err() {
+                tracing::trace!("Client closed the connection before the truncation");
+                return;
+            }
+        }
+        // Wait until the data is written before the body is aborted
+        let _ = future::poll_fn(|cx| sender.poll_ready(cx)).await;
+        sender.abort();
+    });
+
+    Response::from_parts(parts, truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{body::HttpBody, Body, Response};
+
+    use crate::configuration::{
+        Configuration, Fault, FaultKind, FaultSettings, Route, RouteMethod,
+    };
+
+    use super::{get_fault, truncate};
+
+    fn fault(rate: u8, code: u16) -> Fault {
+        Fault {
+            kind: FaultKind::Status,
+            rate,
+            code: Some(code),
+            bytes: None,
+            time: None,
+        }
+    }
+
+    fn configuration(enabled: bool) -> Configuration {
+        Configuration {
+            routes: vec![Route {
+                method: RouteMethod::GET,
+                metadata: Option::None,
+                path: "/a".to_string(),
+                resource: Some("somefile.txt".to_string()),
+                body: None,
+                messages: vec![],
+                faults: vec![fault(100, 503)],
+                throttle: None,
+                request: None,
+            }],
+            faults: Some(FaultSettings {
+                enabled,
+                rules: vec![fault(0, 500), fault(100, 502)],
+            }),
+            ..Configuration::default()
+        }
+    }
+
+    #[test]
+    fn get_fault_should_prefer_route_faults() {
+        let fault = get_fault(&configuration(true), "/a", &RouteMethod::GET);
+
+        assert_eq!(fault.unwrap().code, Some(503));
+    }
+
+    #[test]
+    fn get_fault_should_skip_rules_that_do_not_hit() {
+        let fault = get_fault(&configuration(true), "/b", &RouteMethod::GET);
+
+        assert_eq!(fault.unwrap().code, Some(502));
+    }
+
+    #[test]
+    fn get_fault_should_do_nothing_when_disabled() {
+        let fault = get_fault(&configuration(false), "/a", &RouteMethod::GET);
+
+        assert!(fault.is_none());
+    }
+
+    #[tokio::test]
+    async fn truncate_should_stream_the_first_bytes() {
+        let chunks: Vec<Result<&str, std::io::Error>> = vec![Ok("abc"), Ok("def"), Ok("ghij")];
+        let response = Response::builder()
+            .header(hyper::header::CONTENT_LENGTH, 10)
+            .body(Body::wrap_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+        let fault = Fault {
+            kind: FaultKind::Truncate,
+            bytes: Some(4),
+            ..fault(100, 200)
+        };
+
+        let mut response = truncate(response, &fault);
+        let mut received = vec![];
+        let mut aborted = false;
+        while let Some(data) = response.body_mut().data().await {
+            match data {
+                Ok(data) => received.extend_from_slice(&data),
+                Err(_) => aborted = true,
+            }
+        }
+
+        assert_eq!(received, b"abcd");
+        assert!(aborted);
+        assert_eq!(response.headers()[hyper::header::CONTENT_LENGTH], "10");
+    }
+}
@@ -0,0 +1,18 @@
+//! This contains the logic that changes how a response is sent to the client.
+
+/// This contains the fault injection to test how clients handle misbehaving servers.
+pub mod fault;
+/// This contains the bandwidth limit to simulate slow connections.
+pub mod throttle;
+/// This contains the support for partial content with the `range` header.
+pub mod range;
+/// This contains the conditional requests with `etag` and `last-modified`.
+pub mod conditional;
+/// This contains the CORS handling for clients on other origins.
+pub mod cors;
+/// This contains the compression of responses based on `accept-encoding`.
+pub mod encoding;
+/// This contains the folders that are served as routes.
+pub mod mount;
+/// This contains the hop-by-hop and framing headers that are not forwarded or replayed.
+pub mod headers;
@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::fs;
+
+use crate::configuration::Mount;
+
+/// Characters that are not encoded in the links of a listing
+const LINK: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_');
+
+/// What a mount serves for a uri
+#[derive(Debug, PartialEq, Eq)]
+pub enum MountResponse {
+    /// Location of the file that is served
+    File(String),
+    /// HTML page with the content of a folder
+    Listing(String),
+    /// Folders are redirected to the uri with a trailing slash so that relative links work
+    Redirect(String),
+}
+
+/// Returns the mount with the longest matching path.
+pub fn get_mount<'a>(mounts: &'a [Mount], uri: &str) -> Option<&'a Mount> {
+    let path = get_path(uri);
+
+    mounts
+        .iter()
+        .filter(|m| is_in_mount(m, path))
+        .max_by_key(|m| m.path.len())
+}
+
+/// Checks if the path is the mount path or below it. `/assets` matches `/assets/a.png` but not
+/// `/assetsx/a.png`.
+fn is_in_mount(mount: &Mount, path: &str) -> bool {
+    path.strip_prefix(mount.path.trim_end_matches('/'))
+        .map(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(false)
+}
+
+/// Finds the file or folder in the mount for the uri.
+pub async fn resolve(mount: &Mount, uri: &str) -> Option<MountResponse> {
+    let path = get_path(uri);
+    let relative_path = get_relative_path(mount, path)?;
+    let folder = fs::canonicalize(&mount.folder).await.ok()?;
+    let location = fs::canonicalize(folder.join(&relative_path)).await.ok()?;
+    if !location.starts_with(&folder) {
+        tracing::error!("Path is outside of the mount: {}", uri);
+        return None;
+    }
+
+    let metadata = fs::metadata(&location).await.ok()?;
+    if metadata.is_file() {
+        return Some(MountResponse::File(location.to_string_lossy().to_string()));
+    }
+    if !path.ends_with('/') {
+        return Some(MountResponse::Redirect(path.to_owned() + "/"));
+    }
+
+    for index in &mount.index {
+        let index_location = location.join(index);
+        if fs::metadata(&index_location)
+            .await
+            .map(|m| m.is_file())
+            .unwrap_or(false)
+        {
+            return Some(MountResponse::File(
+                index_location.to_string_lossy().to_string(),
+            ));
+        }
+    }
+
+    if mount.listing {
+        return get_listing(&location, path)
+            .await
+            .map(MountResponse::Listing);
+    }
+
+    None
+}
+
+fn get_path(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Decodes the part of the uri after the mount path. Returns nothing for paths that try to leave
+/// the folder.
+fn get_relative_path(mount: &Mount, path: &str) -> Option<PathBuf> {
+    if !is_in_mount(mount, path) {
+        return None;
+    }
+    let relative = path
+        .strip_prefix(mount.path.trim_end_matches('/'))?
+        .trim_start_matches('/');
+    let mut relative_path = PathBuf::new();
+
+    for segment in relative.split('/').filter(|s| !s.is_empty()) {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if segment == "."
+            || segment == ".."
+            || segment.contains(['/', '\\', '\0'])
+            || Path::new(segment.as_ref()).has_root()
+            || segment.contains(':')
+        {
+            tracing::error!("Invalid path segment for mount: {}", segment);
+            return None;
+        }
+        relative_path.push(segment.as_ref());
+    }
+
+    Some(relative_path)
+}
+
+async fn get_listing(location: &Path, path: &str) -> Option<String> {
+    let mut entries = fs::read_dir(location).await.ok()?;
+    let mut names = vec![];
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false) {
+            name += "/";
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let title = escape_html(path);
+    let mut listing = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<ul>\n"
+    );
+    for name in names {
+        let link = utf8_percent_encode(&name, LINK);
+        listing += &format!("<li><a href=\"{}\">{}</a></li>\n", link, escape_html(&name));
+    }
+    listing += "</ul>\n</body>\n</html>\n";
+
+    Some(listing)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::configuration::Mount;
+
+    use super::{get_mount, get_relative_path};
+
+    fn mount(path: &str) -> Mount {
+        Mount {
+            path: path.to_string(),
+            folder: "./public".to_string(),
+            index: vec!["index.html".to_string()],
+            listing: false,
+        }
+    }
+
+    #[test]
+    fn get_mount_should_use_longest_path() {
+        let mounts = vec![mount("/assets/"), mount("/assets/images/")];
+
+        assert_eq!(
+            get_mount(&mounts, "/assets/images/a.png?v=1").unwrap().path,
+            "/assets/images/"
+        );
+        assert_eq!(get_mount(&mounts, "/assets").unwrap().path, "/assets/");
+        assert!(get_mount(&mounts, "/assetsx/a.png").is_none());
+    }
+
+    #[test]
+    fn get_mount_should_respect_segments_without_trailing_slash() {
+        let mounts = vec![mount("/assets")];
+
+        assert_eq!(get_mount(&mounts, "/assets/a.png").unwrap().path, "/assets");
+        assert_eq!(get_mount(&mounts, "/assets").unwrap().path, "/assets");
+        assert!(get_mount(&mounts, "/assetsx/a.png").is_none());
+        assert_eq!(get_relative_path(&mounts[0], "/assetsx/a.png"), None);
+    }
+
+    #[test]
+    fn get_relative_path_should_decode_segments() {
+        assert_eq!(
+            get_relative_path(&mount("/assets/"), "/assets/css/a%20b.css"),
+            Some(PathBuf::from("css").join("a b.css"))
+        );
+    }
+
+    #[test]
+    fn get_relative_path_should_prevent_path_traversal() {
+        let mount = mount("/assets/");
+
+        assert_eq!(get_relative_path(&mount, "/assets/../moxy.json"), None);
+        assert_eq!(get_relative_path(&mount, "/assets/%2e%2e/moxy.json"), None);
+        assert_eq!(get_relative_path(&mount, "/assets/..%2fmoxy.json"), None);
+        assert_eq!(get_relative_path(&mount, "/assets/..%5cmoxy.json"), None);
+    }
+}
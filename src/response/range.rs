@@ -0,0 +1,310 @@
+use std::{io::SeekFrom, ops::RangeInclusive};
+
+use hyper::{
+    body::Bytes,
+    header::{self, HeaderValue},
+    http::response::Builder,
+    Body, HeaderMap, Method, Response, StatusCode,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::data_loader::Resource;
+
+/// Result of reading the `range` header
+#[derive(Debug, PartialEq, Eq)]
+enum RangeRequest {
+    /// Send the whole resource
+    Full,
+    /// Send only these parts of the resource
+    Partial(Vec<RangeInclusive<u64>>),
+    /// None of the ranges are inside of the resource
+    Unsatisfiable,
+}
+
+/// Creates the response for a file resource. Only the requested ranges are sent when the
+/// request contains a `range` header.
+pub async fn respond(
+    method: &Method,
+    request_headers: &HeaderMap,
+    response: Builder,
+    resource: Resource,
+) -> Response<Body> {
+    let mut response = response.body(Body::empty()).unwrap();
+    let range_request = if method == Method::GET
+        && response.status() == StatusCode::OK
+        && is_range_valid(request_headers, response.headers())
+    {
+        request_headers
+            .get(header::RANGE)
+            .and_then(|r| r.to_str().ok())
+            .map(|r| parse_range(r, resource.length))
+            .unwrap_or(RangeRequest::Full)
+    } else {
+        RangeRequest::Full
+    };
+
+    let length = resource.length;
+    let headers = response.headers_mut();
+    match range_request {
+        RangeRequest::Full => {
+            headers.insert(header::CONTENT_LENGTH, length.into());
+            headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            *response.body_mut() = resource.into_body();
+        }
+        RangeRequest::Unsatisfiable => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{length}")).unwrap(),
+            );
+            headers.insert(header::CONTENT_LENGTH, 0.into());
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&get_content_range(range, length)).unwrap(),
+            );
+            headers.insert(
+                header::CONTENT_LENGTH,
+                (range.end() - range.start() + 1).into(),
+            );
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            *response.body_mut() = single_range(resource.file, range).await;
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = format!("{:016x}", rand::random::<u64>());
+            let content_type = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|c| c.to_str().ok())
+                .unwrap_or("application/octet-stream")
+                .to_owned();
+            let (body, body_length) =
+                multiple_ranges(resource.file, ranges, length, &content_type, &boundary);
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}"))
+                    .unwrap(),
+            );
+            headers.insert(header::CONTENT_LENGTH, body_length.into());
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            *response.body_mut() = body;
+        }
+    };
+
+    response
+}
+
+/// Checks the `if-range` header. Ranges are only used when the resource did not change.
+fn is_range_valid(request_headers: &HeaderMap, response_headers: &HeaderMap) -> bool {
+    let Some(if_range) = request_headers
+        .get(header::IF_RANGE)
+        .and_then(|i| i.to_str().ok())
+    else {
+        return true;
+    };
+
+    if if_range.starts_with('"') {
+        return response_headers
+            .get(header::ETAG)
+            .and_then(|e| e.to_str().ok())
+            .map(|etag| etag == if_range)
+            .unwrap_or(false);
+    }
+
+    let last_modified = response_headers
+        .get(header::LAST_MODIFIED)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| httpdate::parse_http_date(l).ok());
+    let if_range = httpdate::parse_http_date(if_range).ok();
+
+    last_modified.is_some() && last_modified == if_range
+}
+
+fn parse_range(range: &str, length: u64) -> RangeRequest {
+    let Some(range) = range.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = vec![];
+    for spec in range.split(',') {
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            let Ok(suffix) = end.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix > 0 && length > 0 {
+                ranges.push(length.saturating_sub(suffix)..=length - 1);
+            }
+            continue;
+        }
+
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = if end.is_empty() {
+            u64::MAX
+        } else if let Ok(end) = end.parse::<u64>() {
+            end
+        } else {
+            return RangeRequest::Full;
+        };
+        if end < start {
+            return RangeRequest::Full;
+        }
+
+        if start < length {
+            ranges.push(start..=end.min(length - 1));
+        }
+    }
+
+    if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+fn get_content_range(range: &RangeInclusive<u64>, length: u64) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), length)
+}
+
+async fn single_range(mut file: File, range: &RangeInclusive<u64>) -> Body {
+    if let Err(e) = file.seek(SeekFrom::Start(*range.start())).await {
+        tracing::error!("Unable to read range of the resource: {}", e);
+        return Body::empty();
+    }
+
+    Body::wrap_stream(ReaderStream::new(
+        file.take(range.end() - range.start() + 1),
+    ))
+}
+
+/// Creates a `multipart/byteranges` body and its length.
+fn multiple_ranges(
+    mut file: File,
+    ranges: Vec<RangeInclusive<u64>>,
+    length: u64,
+    content_type: &str,
+    boundary: &str,
+) -> (Body, u64) {
+    let parts: Vec<(String, RangeInclusive<u64>)> = ranges
+        .into_iter()
+        .map(|range| {
+            let part_header = format!(
+                "\r\n--{boundary}\r\ncontent-type: {content_type}\r\ncontent-range: {}\r\n\r\n",
+                get_content_range(&range, length)
+            );
+            (part_header, range)
+        })
+        .collect();
+    let end = format!("\r\n--{boundary}--\r\n");
+    let body_length = parts
+        .iter()
+        .map(|(part_header, range)| part_header.len() as u64 + range.end() - range.start() + 1)
+        .sum::<u64>()
+        + end.len() as u64;
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for (part_header, range) in parts {
+            if sender.send_data(Bytes::from(part_header)).await.is_err() {
+                return;
+            }
+
+            let mut remaining = range.end() - range.start() + 1;
+            if file.seek(SeekFrom::Start(*range.start())).await.is_err() {
+                sender.abort();
+                return;
+            }
+            while remaining > 0 {
+                let mut buffer = vec![0; remaining.min(64 * 1024) as usize];
+                match file.read(&mut buffer).await {
+                    Ok(0) | Err(_) => {
+                        tracing::error!("Unable to read range of the resource");
+                        sender.abort();
+                        return;
+                    }
+                    Ok(read) => {
+                        buffer.truncate(read);
+                        remaining -= read as u64;
+                        if sender.send_data(Bytes::from(buffer)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+
+        let _ = sender.send_data(Bytes::from(end)).await;
+    });
+
+    (body, body_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header, HeaderMap};
+
+    use super::{is_range_valid, parse_range, RangeRequest};
+
+    #[test]
+    fn parse_range_should_read_single_range() {
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            RangeRequest::Partial(vec![0..=499])
+        );
+    }
+
+    #[test]
+    fn parse_range_should_read_open_and_suffix_ranges() {
+        assert_eq!(
+            parse_range("bytes=900-, -100", 1000),
+            RangeRequest::Partial(vec![900..=999, 900..=999])
+        );
+    }
+
+    #[test]
+    fn parse_range_should_limit_end_to_length() {
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            RangeRequest::Partial(vec![500..=999])
+        );
+    }
+
+    #[test]
+    fn parse_range_should_detect_unsatisfiable_range() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn parse_range_should_ignore_invalid_range() {
+        assert_eq!(parse_range("bytes=500-100", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn is_range_valid_should_compare_etag() {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::IF_RANGE, "\"abc\"".parse().unwrap());
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(header::ETAG, "\"abc\"".parse().unwrap());
+
+        assert!(is_range_valid(&request_headers, &response_headers));
+
+        response_headers.insert(header::ETAG, "\"def\"".parse().unwrap());
+
+        assert!(!is_range_valid(&request_headers, &response_headers));
+    }
+}
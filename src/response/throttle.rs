@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use hyper::{body::HttpBody, Body, Response};
+
+use crate::configuration::Throttle;
+
+/// Sends the body in chunks so that the configured bytes per second are not exceeded.
+pub fn throttle(response: Response<Body>, throttle: Option<&Throttle>) -> Response<Body> {
+    let Some(throttle) = throttle.cloned() else {
+        return response;
+    };
+    if throttle.bytes_per_second == 0 {
+        return response;
+    }
+
+    let (parts, mut body) = response.into_parts();
+    let (mut sender, throttled_body) = Body::channel();
+    let chunk_size = get_chunk_size(&throttle);
+
+    tokio::spawn(async move {
+        while let Some(data) = body.data().await {
+            let Ok(data) = data else {
+                tracing::error!("Unable to read body for throttling");
+                sender.abort();
+                return;
+            };
+
+            for start in (0..data.len()).step_by(chunk_size) {
+                let chunk = data.slice(start..data.len().min(start + chunk_size));
+                tokio::time::sleep(get_delay(chunk.len(), &throttle)).await;
+
+                if sender.send_data(chunk).await.is_err() {
+                    tracing::trace!("Client closed the connection while throttling");
+                    return;
+                }
+            }
+        }
+    });
+
+    Response::from_parts(parts, throttled_body)
+}
+
+fn get_chunk_size(throttle: &Throttle) -> usize {
+    throttle
+        .chunk_size
+        .unwrap_or(throttle.bytes_per_second / 10)
+        .max(1)
+}
+
+fn get_delay(length: usize, throttle: &Throttle) -> Duration {
+    Duration::from_secs_f64(length as f64 / throttle.bytes_per_second as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::configuration::Throttle;
+
+    use super::{get_chunk_size, get_delay};
+
+    #[test]
+    fn get_chunk_size_should_default_to_a_tenth() {
+        let throttle = Throttle {
+            bytes_per_second: 1000,
+            chunk_size: None,
+        };
+
+        assert_eq!(get_chunk_size(&throttle), 100);
+    }
+
+    #[test]
+    fn get_chunk_size_should_never_be_zero() {
+        let throttle = Throttle {
+            bytes_per_second: 5,
+            chunk_size: None,
+        };
+
+        assert_eq!(get_chunk_size(&throttle), 1);
+    }
+
+    #[test]
+    fn get_delay_should_match_bytes_per_second() {
+        let throttle = Throttle {
+            bytes_per_second: 1000,
+            chunk_size: Some(250),
+        };
+
+        assert_eq!(get_delay(250, &throttle), Duration::from_millis(250));
+    }
+}
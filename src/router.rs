@@ -2,28 +2,35 @@
 use futures_util::StreamExt;
 use futures_util::{sink::SinkExt, stream::FuturesUnordered};
 use hyper::upgrade::Upgraded;
+use hyper::http::response::Builder;
 use hyper::HeaderMap;
 use hyper_tungstenite::WebSocketStream;
 use rayon::prelude::*;
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
 
 use hyper::{
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Request, Response, Server,
 };
 use hyper_tungstenite::{tungstenite::Message, HyperWebsocket};
 use tokio::sync::Mutex;
 
-use crate::configuration::{Metadata, WsMessage};
+use crate::configuration::{
+    FaultKind, InlineBody, Metadata, Mount, Route, Throttle, WsMessage,
+};
 use crate::{
-    builder::{self, storage},
+    builder::{self, coalesce, persist, request::forward, storage},
     configuration::{self, BuildMode, Configuration, RouteMethod, WsMessageType},
     data_loader,
+    response::{conditional, cors, encoding, fault, headers, mount, range, throttle},
 };
 
+/// How long a shutdown waits for running recordings before the configuration is saved.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Start webserver using hyper
-pub async fn start() {
-    let mut config = configuration::get_configuration().await;
+pub async fn start(mut config: Configuration) {
     tracing::trace!("Config: {:?}", config);
     if config.host.is_none() {
         config.host = Configuration::default().host;
@@ -35,13 +42,17 @@ pub async fn start() {
     let config = Arc::new(Mutex::new(config));
 
     if let Ok(addr) = addr {
-        let make_service = make_service_fn(move |_| {
-            let config = config.clone();
+        storage::clear_temp_files(storage::DB_FOLDER).await;
+        persist::start_writer(config.clone());
+        let service_config = config.clone();
+        let make_service = make_service_fn(move |connection: &AddrStream| {
+            let config = service_config.clone();
+            let client = connection.remote_addr();
 
             async move {
                 Ok::<_, hyper::Error>(service_fn(move |req| {
                     let config = config.clone();
-                    async move { check_ws(req, config, no_ssl_check).await }
+                    async move { handle(req, client, config, no_ssl_check).await }
                 }))
             }
         });
@@ -49,39 +60,229 @@ pub async fn start() {
         tracing::info!("Starting http server on http://{addr}");
         let server = Server::bind(&addr).serve(make_service);
 
-        // Run this server for... forever!
-        if let Err(e) = server.await {
-            tracing::error!("server error: {}", e);
+        // Run this server for... forever! Or until it is stopped.
+        tokio::select! {
+            result = server => {
+                if let Err(e) = result {
+                    tracing::error!("server error: {}", e);
+                }
+            }
+            _ = shutdown_signal() => tracing::info!("Shutting down"),
+        }
+        let waited = tokio::time::timeout(SHUTDOWN_TIMEOUT, persist::wait_for_recordings()).await;
+        if waited.is_err() {
+            tracing::error!("Stopped waiting for recordings after {SHUTDOWN_TIMEOUT:?}");
+        }
+        if let Err(e) = persist::flush(config).await {
+            tracing::error!("Unable to save configuration: {}", e);
         }
     } else {
         tracing::error!("Unable to start application with an invalid host");
     }
 }
 
+/// Waits for Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Handles moxy's own routes and CORS around the normal request handling.
+///
+/// Returning an error makes hyper close the connection without sending a response.
+async fn handle(
+    mut request: Request<Body>,
+    client: SocketAddr,
+    config: Arc<Mutex<Configuration>>,
+    no_ssl_check: bool,
+) -> Result<Response<Body>, Error> {
+    if let Some(response) = admin(&request, config.clone()).await {
+        return Ok(response);
+    }
+
+    let forwarding = config.lock().await.forwarding.clone();
+    if let Some(forwarding) = forwarding {
+        forward::add_forwarded_headers(&forwarding, request.headers_mut(), Some(client));
+    }
+
+    let cors_settings = config.lock().await.cors.clone();
+    let Some(cors_settings) = cors_settings else {
+        return handle_fault(request, config, no_ssl_check).await;
+    };
+    if cors::is_preflight(&request) {
+        tracing::info!("Answer preflight: {}", request.uri());
+        return Ok(cors::preflight(&cors_settings, request.headers()));
+    }
+
+    let origin = request.headers().get(hyper::header::ORIGIN).cloned();
+    let mut response = handle_fault(request, config, no_ssl_check).await?;
+    cors::add_headers(&cors_settings, origin.as_ref(), response.headers_mut());
+
+    Ok(response)
+}
+
+/// Applies fault rules around the normal request handling.
+async fn handle_fault(
+    request: Request<Body>,
+    config: Arc<Mutex<Configuration>>,
+    no_ssl_check: bool,
+) -> Result<Response<Body>, Error> {
+    let uri = request.uri().path_and_query().unwrap().to_string();
+    let method = RouteMethod::from(request.method());
+    let fault = fault::get_fault(&*config.lock().await, &uri, &method);
+    let Some(fault) = fault else {
+        return Ok(check_ws(request, config, no_ssl_check).await?);
+    };
+
+    tracing::info!("Inject fault: {:?}", fault);
+    match fault.kind {
+        FaultKind::Status => Ok(fault::status(&fault)),
+        FaultKind::Reset => Err("Connection reset by fault injection".into()),
+        FaultKind::Hang => {
+            fault::hang(&fault).await;
+            Err("Connection closed by fault injection".into())
+        }
+        FaultKind::Truncate => {
+            let response = check_ws(request, config, no_ssl_check).await?;
+            Ok(fault::truncate(response, &fault))
+        }
+    }
+}
+
+/// Routes to control moxy at runtime.
+async fn admin(
+    request: &Request<Body>,
+    config: Arc<Mutex<Configuration>>,
+) -> Option<Response<Body>> {
+    let enabled = match request.uri().path() {
+        "/_moxy/faults/enable" => true,
+        "/_moxy/faults/disable" => false,
+        "/_moxy/flush" => return Some(flush(config).await),
+        _ => return None,
+    };
+
+    fault::set_enabled(enabled);
+    tracing::info!("Fault injection enabled: {}", enabled);
+
+    Some(Response::builder().status(204).body(Body::empty()).unwrap())
+}
+
+/// Waits for running recordings and saves pending changes of the configuration, so that tests
+/// can read `moxy.json`.
+async fn flush(config: Arc<Mutex<Configuration>>) -> Response<Body> {
+    persist::wait_for_recordings().await;
+    let status = match persist::flush(config).await {
+        Ok(()) => 204,
+        Err(e) => {
+            tracing::error!("Unable to save configuration: {}", e);
+            500
+        }
+    };
+
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
 /// Call data_loader or builder depending on if the route exists or not.
 async fn endpoint(
     config_a: Arc<Mutex<Configuration>>,
     uri: &str,
     method: hyper::Method,
     header: HeaderMap,
-    body: hyper::Body,
+    mut body: hyper::Body,
     no_ssl_check: bool,
 ) -> Result<Response<Body>, Infallible> {
     tracing::info!("{}", uri);
+    let flight = match join_flight(&config_a, uri, &method).await {
+        Ok(flight) => flight,
+        Err(response) => {
+            let mut response = encoding::compress(&header, response);
+            if method == hyper::Method::HEAD {
+                *response.body_mut() = Body::empty();
+            }
+            return Ok(response);
+        }
+    };
     let configc = config_a.clone();
     let mut config = configc.lock().await.to_owned();
-    let (route, parameter) =
+    let (mut route, mut parameter) =
         configuration::get_route(&config.routes, uri, &RouteMethod::from(method.clone()));
+    if route.is_none() && method == hyper::Method::HEAD {
+        tracing::trace!("Answer HEAD with the GET route");
+        (route, parameter) = configuration::get_route(&config.routes, uri, &RouteMethod::GET);
+    }
 
     let Some(route) = route else {
-         if config.build_mode == Some(BuildMode::Write) {
-             return builder::core::build_response(config_a, uri, method, header, body, no_ssl_check).await
+         if let Some(mount) = mount::get_mount(&config.mounts, uri) {
+             if let Some(response) = serve_mount(&method, &header, mount, uri, config.throttle.as_ref()).await {
+                 return Ok(response);
+             }
+         }
+         if matches!(config.build_mode, Some(BuildMode::Write | BuildMode::Passthrough | BuildMode::Refresh)) {
+             let head = method == hyper::Method::HEAD;
+             let response = builder::core::build_response(config_a, uri, method, header.clone(), body, no_ssl_check, flight).await?;
+             let mut response = encoding::compress(&header, response);
+             if head {
+                 *response.body_mut() = Body::empty();
+                 return Ok(response);
+             }
+             return Ok(throttle::throttle(response, config.throttle.as_ref()));
          } else {
              tracing::info!("Resource not found and build mode disabled");
              let response = Response::builder().status(404).body(Body::empty()).unwrap();
              return Ok(response);
          }
      };
+    if config.build_mode == Some(BuildMode::Refresh)
+        && route.body.is_none()
+        && method != hyper::Method::HEAD
+    {
+        let payload = match hyper::body::to_bytes(body).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!("Unable to read request body: {}", e);
+                let response = Response::builder().status(400).body(Body::empty()).unwrap();
+                return Ok(response);
+            }
+        };
+        let refreshed_route = storage::RefreshedRoute {
+            path: route.path.clone(),
+            parameter: parameter.map(str::to_owned),
+        };
+        if let Some(response) = builder::core::refresh_response(
+            config_a.clone(),
+            uri,
+            refreshed_route,
+            method.clone(),
+            header.clone(),
+            Body::from(payload.clone()),
+            no_ssl_check,
+        )
+        .await
+        {
+            let response = encoding::compress(&header, response);
+            return Ok(throttle::throttle(response, route.throttle.as_ref().or(config.throttle.as_ref())));
+        }
+        body = Body::from(payload);
+    }
+    if let Some(inline_body) = &route.body {
+        let response = get_inline_response(route, inline_body);
+        let mut response = encoding::compress(&header, response);
+        if method == hyper::Method::HEAD {
+            *response.body_mut() = Body::empty();
+            return Ok(response);
+        }
+        return Ok(throttle::throttle(response, route.throttle.as_ref().or(config.throttle.as_ref())));
+    }
+
     let data = data_loader::load(route, parameter);
     let Some(data) = data.await else {
         if let Some(x) = config.routes.iter().position(|c| c == route) {
@@ -89,8 +290,15 @@ async fn endpoint(
             config.routes.remove(x);
         }
 
-        if config.build_mode == Some(BuildMode::Write) {
-            return builder::core::build_response(config_a, uri, method, header, body, no_ssl_check).await;
+        if matches!(config.build_mode, Some(BuildMode::Write | BuildMode::Passthrough | BuildMode::Refresh)) {
+            let head = method == hyper::Method::HEAD;
+            let response = builder::core::build_response(config_a, uri, method, header.clone(), body, no_ssl_check, flight).await?;
+            let mut response = encoding::compress(&header, response);
+            if head {
+                *response.body_mut() = Body::empty();
+                return Ok(response);
+            }
+            return Ok(throttle::throttle(response, config.throttle.as_ref()));
         } else {
             tracing::error!("Will build new route for missing file");
             let response = Response::builder().status(404).body(Body::empty()).unwrap();
@@ -98,32 +306,210 @@ async fn endpoint(
         }
     };
     let metadata = route.metadata.to_owned().unwrap_or_default();
-    let mut resp_build = Response::builder().status(metadata.code).header(
-        "content-type",
-        get_content_type_with_fallback(metadata.header.clone(), route.resource.clone()),
-    );
-
-    for (key, value) in metadata.header.into_iter() {
-        if let Some(key) = key {
-            resp_build = resp_build.header(key, value);
+    let resp_build = get_response_builder(route);
+
+    Ok(serve_resource(
+        &method,
+        &header,
+        metadata.code,
+        resp_build,
+        data,
+        route.throttle.as_ref().or(config.throttle.as_ref()),
+    )
+    .await)
+}
+
+/// While recording, concurrent GET and HEAD requests of a URI that is not recorded yet share one
+/// call to the remote. Returns the flight when this request calls the remote. Requests that
+/// waited for the response get it as `Err` and send it as is. Requests that arrived after the
+/// response wait until it was recorded, so that they are served from the recording.
+async fn join_flight(
+    config_a: &Arc<Mutex<Configuration>>,
+    uri: &str,
+    method: &hyper::Method,
+) -> Result<Option<coalesce::Guard>, Response<Body>> {
+    let Some(key) = coalesce::Key::new(method, uri) else {
+        return Ok(None);
+    };
+    loop {
+        let route = {
+            let config = config_a.lock().await;
+            if !matches!(config.build_mode, Some(BuildMode::Write | BuildMode::Refresh))
+                || mount::get_mount(&config.mounts, uri).is_some()
+            {
+                return Ok(None);
+            }
+            get_route(&config.routes, uri, method)
+        };
+        if is_recorded(route).await {
+            return Ok(None);
+        }
+
+        match coalesce::join(key.clone()) {
+            coalesce::Flight::Leader(guard) => return Ok(Some(guard)),
+            coalesce::Flight::Follower(follower) => {
+                tracing::info!("Wait for the running request to {}", uri);
+                match follower.wait().await {
+                    Some(Ok(shared)) => {
+                        let response =
+                            builder::core::get_response(shared.headers, shared.code, shared.body);
+                        return Err(response.unwrap());
+                    }
+                    Some(Err(code)) => {
+                        let response = Response::builder().status(code).body(Body::empty());
+                        return Err(response.unwrap());
+                    }
+                    None => (),
+                }
+            }
         }
     }
+}
 
-    let response = resp_build.body(Body::from(data)).unwrap();
+/// Returns the route and parameter that answer the request. HEAD is answered with the GET route.
+fn get_route(
+    routes: &[Route],
+    uri: &str,
+    method: &hyper::Method,
+) -> Option<(Route, Option<String>)> {
+    let (mut route, mut parameter) =
+        configuration::get_route(routes, uri, &RouteMethod::from(method.clone()));
+    if route.is_none() && method == hyper::Method::HEAD {
+        (route, parameter) = configuration::get_route(routes, uri, &RouteMethod::GET);
+    }
 
-    Ok(response)
+    route.map(|route| (route.clone(), parameter.map(str::to_owned)))
 }
 
-fn get_content_type_with_fallback(headers: HeaderMap, resource: Option<String>) -> String {
-    return headers
+/// Returns true when the request can be served from the route. Routes whose file is missing are
+/// recorded again.
+async fn is_recorded(route: Option<(Route, Option<String>)>) -> bool {
+    match route {
+        Some((route, _)) if route.body.is_some() => true,
+        Some((route, parameter)) => data_loader::load(&route, parameter.as_deref())
+            .await
+            .is_some(),
+        None => false,
+    }
+}
+
+/// Sends a file to the client with support for conditional and range requests.
+async fn serve_resource(
+    method: &hyper::Method,
+    header: &HeaderMap,
+    code: u16,
+    mut resp_build: Builder,
+    mut data: data_loader::Resource,
+    throttle: Option<&Throttle>,
+) -> Response<Body> {
+    if code == 200 {
+        resp_build = conditional::add_validators(resp_build, &mut data).await;
+        if let Some(response) = conditional::not_modified(method, header, &resp_build) {
+            return response;
+        }
+    }
+
+    let response = range::respond(method, header, resp_build, data).await;
+    // HEAD gets the same headers as GET, so the encoding is applied before the body is dropped
+    let mut response = encoding::compress(header, response);
+    if method == hyper::Method::HEAD {
+        *response.body_mut() = Body::empty();
+        return response;
+    }
+
+    throttle::throttle(response, throttle)
+}
+
+/// Serves a file or folder listing of a mount.
+async fn serve_mount(
+    method: &hyper::Method,
+    header: &HeaderMap,
+    mount: &Mount,
+    uri: &str,
+    throttle: Option<&Throttle>,
+) -> Option<Response<Body>> {
+    let response = match mount::resolve(mount, uri).await? {
+        mount::MountResponse::File(location) => {
+            let data = data_loader::file(&location).await.ok()?;
+            let resp_build = Response::builder()
+                .status(200)
+                .header("content-type", storage::get_content_type(location));
+
+            return Some(serve_resource(method, header, 200, resp_build, data, throttle).await);
+        }
+        mount::MountResponse::Redirect(location) => Response::builder()
+            .status(301)
+            .header("location", location)
+            .body(Body::empty())
+            .unwrap(),
+        mount::MountResponse::Listing(listing) => Response::builder()
+            .status(200)
+            .header("content-type", "text/html; charset=utf-8")
+            .header("content-length", listing.len())
+            .body(if method == hyper::Method::HEAD {
+                Body::empty()
+            } else {
+                Body::from(listing)
+            })
+            .unwrap(),
+    };
+
+    Some(response)
+}
+
+/// Creates a response with the recorded status code and headers of the route.
+fn get_response_builder(route: &Route) -> Builder {
+    let metadata = route.metadata.to_owned().unwrap_or_default();
+    let content_type = get_content_type_with_fallback(metadata.header.clone(), route);
+    let mut resp_build = Response::builder().status(metadata.code);
+
+    // The framing of the recording does not match the body that is sent
+    let mut header = metadata.header;
+    headers::remove_framing(&mut header);
+    if !header.contains_key(hyper::header::CONTENT_TYPE) {
+        if let Ok(content_type) = content_type.parse() {
+            header.insert(hyper::header::CONTENT_TYPE, content_type);
+        }
+    }
+    if let Some(headers) = resp_build.headers_mut() {
+        *headers = header;
+    }
+
+    resp_build
+}
+
+/// Serves a body that is stored in the configuration instead of a file.
+fn get_inline_response(route: &Route, body: &InlineBody) -> Response<Body> {
+    let data = match body.get_data() {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Unable to decode inline body of {}: {}", route.path, e);
+            return Response::builder().status(500).body(Body::empty()).unwrap();
+        }
+    };
+
+    let length = data.len();
+    let mut response = get_response_builder(route).body(Body::from(data)).unwrap();
+    response
+        .headers_mut()
+        .insert(hyper::header::CONTENT_LENGTH, length.into());
+
+    response
+}
+
+fn get_content_type_with_fallback(headers: HeaderMap, route: &Route) -> String {
+    headers
         .get("content-type")
         .map(|v| v.to_str().unwrap_or_default())
         .map(|c| c.to_owned())
         .unwrap_or_else(|| {
-            tracing::info!("Guessing content-type based on the file resource. Becaue it was not specified in the headers");
-            storage::get_content_type(resource
+            tracing::info!("Guessing content-type based on the route. Becaue it was not specified in the headers");
+            if let Some(body) = &route.body {
+                return body.get_content_type().to_owned();
+            }
+            storage::get_content_type(route.resource.clone()
                                              .expect("save here because there will never be data without a resource",))
-            });
+            })
 }
 
 async fn check_ws(
@@ -178,16 +564,37 @@ async fn endpoint_ws(
     no_ssl_check: bool,
 ) -> Result<(), Error> {
     let config = config_a.clone();
-    let mut config = config.lock().await.to_owned();
+    let config = config.lock().await.to_owned();
+    let mut metadata = metadata;
+    if let (Some(metadata), Some(remote)) = (metadata.as_mut(), &config.remote) {
+        forward::rewrite_for_remote(
+            &config.forwarding.clone().unwrap_or_default(),
+            &mut metadata.header,
+            remote,
+        );
+    }
     let (Some(route), _parameter) = configuration::get_route(&config.routes, uri, &RouteMethod::WS) else {
-      if config.build_mode == Some(BuildMode::Write) {
+      if matches!(config.build_mode, Some(BuildMode::Write | BuildMode::Refresh)) {
         if let Some(remote) = &config.remote {
             tracing::trace!("Start ws build");
-            let route = builder::ws::build_ws(uri, metadata, remote.to_owned(), websocket, no_ssl_check).await;
+            let settings = config.upstream.clone().unwrap_or_default().get_settings(remote);
+            let redaction = config.redaction.clone().unwrap_or_default();
+            let route = builder::ws::build_ws(uri, metadata, remote.to_owned(), websocket, no_ssl_check, settings, redaction).await;
             if let Ok(route) = route {
-                config.routes.push(route);
+                config_a.lock().await.routes.push(route);
+                persist::mark_changed();
             }
-            configuration::save_configuration(config.to_owned()).await?;
+        } else {
+            tracing::info!(
+                "There is no configuration for the url: {}, and there is no remote specified",
+                &uri.to_string()
+            );
+        }
+      } else if config.build_mode == Some(BuildMode::Passthrough) {
+        if let Some(remote) = &config.remote {
+            tracing::trace!("Start ws passthrough");
+            let settings = config.upstream.clone().unwrap_or_default().get_settings(remote);
+            builder::ws::proxy_ws(uri, metadata, remote.to_owned(), websocket, no_ssl_check, settings).await?;
         } else {
             tracing::info!(
                 "There is no configuration for the url: {}, and there is no remote specified",
@@ -298,12 +705,280 @@ async fn send_ws_messages(
     mut websocket: WebSocketStream<Upgraded>,
 ) {
     loop {
-        match rx.try_recv() {
-            Ok(message) => match websocket.send(message).await {
+        if let Ok(message) = rx.try_recv() {
+            match websocket.send(message).await {
                 Ok(_) => tracing::trace!("Sent message"),
                 Err(_) => tracing::error!("Failed to send message"),
-            },
-            Err(_) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use hyper::{
+        body, header,
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Response, Server,
+    };
+    use tokio::sync::Mutex;
+
+    use crate::configuration::{
+        BuildMode, Configuration, Metadata, Route, RouteMethod, Upstream, UpstreamSettings,
+    };
+
+    use super::endpoint;
+
+    fn temp_folder() -> PathBuf {
+        std::env::temp_dir().join(format!("moxy-{:016x}", rand::random::<u64>()))
+    }
+
+    /// Starts a remote that answers every request with the status after the delay. Returns its
+    /// url and the number of requests that it received.
+    fn start_remote(status: u16, delay: Duration) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let make_service = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        tokio::time::sleep(delay).await;
+                        let response = Response::builder().status(status);
+                        Ok::<_, Infallible>(response.body(Body::from("remote")).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let remote = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        (remote, hits)
+    }
+
+    /// Lists all files and folders below the folder.
+    fn list_files(folder: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(folder).into_iter().flatten().flatten() {
+            files.push(entry.path());
+            files.extend(list_files(&entry.path()));
         }
+        files.sort();
+
+        files
+    }
+
+    /// Passes a request for an unknown route to the remote without retries.
+    async fn pass(remote: String, read_timeout: &str) -> u16 {
+        let config = Arc::new(Mutex::new(Configuration {
+            remote: Some(remote),
+            build_mode: Some(BuildMode::Passthrough),
+            upstream: Some(Upstream {
+                settings: UpstreamSettings {
+                    read_timeout: Some(read_timeout.to_string()),
+                    retries: Some(0),
+                    ..UpstreamSettings::default()
+                },
+                ..Upstream::default()
+            }),
+            ..Configuration::default()
+        }));
+
+        let response = endpoint(
+            config,
+            "/unknown",
+            hyper::Method::GET,
+            HeaderMap::new(),
+            Body::empty(),
+            false,
+        )
+        .await
+        .unwrap();
+
+        response.status().as_u16()
+    }
+
+    #[tokio::test]
+    async fn endpoint_should_answer_502_when_the_remote_is_unreachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert_eq!(pass(remote, "1s").await, 502);
+    }
+
+    #[tokio::test]
+    async fn endpoint_should_answer_504_when_the_remote_does_not_respond() {
+        let (remote, _hits) = start_remote(200, Duration::from_secs(5));
+
+        assert_eq!(pass(remote, "100ms").await, 504);
+    }
+
+    #[tokio::test]
+    async fn endpoint_should_proxy_unknown_routes_in_passthrough_mode() {
+        let (remote, hits) = start_remote(200, Duration::ZERO);
+        let config = Arc::new(Mutex::new(Configuration {
+            remote: Some(remote),
+            build_mode: Some(BuildMode::Passthrough),
+            ..Configuration::default()
+        }));
+        let db = list_files(Path::new("./db"));
+
+        let response = endpoint(
+            config.clone(),
+            "/passthrough",
+            hyper::Method::GET,
+            HeaderMap::new(),
+            Body::empty(),
+            false,
+        )
+        .await
+        .unwrap();
+        let (response, payload) = response.into_parts();
+        let payload = body::to_bytes(payload).await.unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(payload, "remote");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(config.lock().await.routes.is_empty());
+        assert_eq!(list_files(Path::new("./db")), db);
+    }
+
+    /// Sends concurrent GET requests in write mode to a remote that fails. Returns the status
+    /// codes and the number of requests that reached the remote.
+    async fn record_concurrently(routes: Vec<Route>, uri: &'static str) -> (Vec<u16>, usize) {
+        let (remote, hits) = start_remote(500, Duration::from_millis(200));
+        let config = Arc::new(Mutex::new(Configuration {
+            remote: Some(remote),
+            build_mode: Some(BuildMode::Write),
+            routes,
+            upstream: Some(Upstream {
+                settings: UpstreamSettings {
+                    retries: Some(0),
+                    ..UpstreamSettings::default()
+                },
+                ..Upstream::default()
+            }),
+            ..Configuration::default()
+        }));
+
+        let requests = (0..5).map(|_| {
+            endpoint(
+                config.clone(),
+                uri,
+                hyper::Method::GET,
+                HeaderMap::new(),
+                Body::empty(),
+                false,
+            )
+        });
+        let mut codes = vec![];
+        for response in futures_util::future::join_all(requests).await {
+            let (response, payload) = response.unwrap().into_parts();
+            assert_eq!(body::to_bytes(payload).await.unwrap(), "remote");
+            codes.push(response.status.as_u16());
+        }
+
+        (codes, hits.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn endpoint_should_share_the_remote_response_with_concurrent_requests() {
+        let (codes, hits) = record_concurrently(vec![], "/coalesced").await;
+
+        assert_eq!(codes, vec![500; 5]);
+        assert_eq!(hits, 1);
+    }
+
+    #[tokio::test]
+    async fn endpoint_should_share_the_remote_response_when_the_recording_is_missing() {
+        let resource = temp_folder().join("missing.txt");
+        let route = Route {
+            method: RouteMethod::GET,
+            metadata: None,
+            path: "/coalesced/missing".to_string(),
+            resource: Some(resource.to_string_lossy().into_owned()),
+            body: None,
+            messages: vec![],
+            faults: vec![],
+            request: None,
+            throttle: None,
+        };
+
+        let (codes, hits) = record_concurrently(vec![route], "/coalesced/missing").await;
+
+        assert_eq!(codes, vec![500; 5]);
+        assert_eq!(hits, 1);
+    }
+
+    #[tokio::test]
+    async fn endpoint_should_answer_head_with_the_get_route() {
+        let folder = temp_folder();
+        tokio::fs::create_dir_all(&folder).await.unwrap();
+        let resource = folder.join("head.txt");
+        tokio::fs::write(&resource, "hello").await.unwrap();
+        let mut recorded = HeaderMap::new();
+        recorded.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        let config = Arc::new(Mutex::new(Configuration {
+            routes: vec![Route {
+                method: RouteMethod::GET,
+                metadata: Some(Metadata {
+                    code: 200,
+                    header: recorded,
+                }),
+                path: "/head".to_string(),
+                resource: Some(resource.to_string_lossy().into_owned()),
+                body: None,
+                messages: vec![],
+                faults: vec![],
+                request: None,
+                throttle: None,
+            }],
+            ..Configuration::default()
+        }));
+
+        let get = endpoint(
+            config.clone(),
+            "/head",
+            hyper::Method::GET,
+            HeaderMap::new(),
+            Body::empty(),
+            false,
+        )
+        .await
+        .unwrap();
+        let head = endpoint(
+            config,
+            "/head",
+            hyper::Method::HEAD,
+            HeaderMap::new(),
+            Body::empty(),
+            false,
+        )
+        .await
+        .unwrap();
+        let (head, head_body) = head.into_parts();
+        let (get, get_body) = get.into_parts();
+        let head_body = body::to_bytes(head_body).await.unwrap();
+        let get_body = body::to_bytes(get_body).await.unwrap();
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+
+        assert_eq!(get_body, "hello");
+        assert_eq!(head.status, get.status);
+        assert_eq!(head.headers, get.headers);
+        assert_eq!(head.headers[header::CONTENT_LENGTH], "5");
+        assert!(head_body.is_empty());
     }
 }